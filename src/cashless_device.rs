@@ -3,6 +3,7 @@ use crate::MDBStatus;
 use crate::Mdb;
 
 use embedded_io_async::{Read, Write};
+use embassy_time::{Duration, Instant};
 
 use defmt::*;
 
@@ -21,6 +22,8 @@ const POLL_CMD: u8 = 0x12;
 const POLL_REPLY_JUST_RESET: u8 = 0x00;
 const POLL_REPLY_READER_CONFIG_DATA: u8 = 0x01;
 const POLL_REPLY_DISPLAY_REQUEST: u8 = 0x02;
+//Most text a display request can carry, given the 36 byte MDB message limit
+pub const MAX_DISPLAY_CHARS: usize = 32;
 const POLL_REPLY_BEGIN_SESSION: u8 = 0x03;
 const POLL_REPLY_SESSION_CANCEL_REQUEST: u8 = 0x04;
 const POLL_REPLY_VEND_APPROVED: u8 = 0x05;
@@ -38,6 +41,11 @@ const POLL_REPLY_USER_FILE_DATA: u8 = 0x10;
 const POLL_REPLY_TIME_DATE_REQUEST: u8 = 0x11;
 const POLL_REPLY_DATA_ENTRY_REQUEST: u8 = 0x12;
 const POLL_REQUEST_DATA_ENTRY_CANCEL: u8 = 0x13;
+//2019 L3 additions
+const POLL_REPLY_REMOTE_VEND_REQUEST: u8 = 0x14;
+const POLL_REPLY_BASKET_APPROVED: u8 = 0x15;
+const POLL_REPLY_COUPON_REPORT: u8 = 0x16;
+const POLL_REPLY_BEGIN_SESSION_REFUSED: u8 = 0x17;
 //We do not support FTL
 const POLL_REPLY_DIAGNOSTICS: u8 = 0xFF;
 
@@ -50,6 +58,14 @@ const VEND_FAILURE: u8 = 0x03;
 const VEND_SESSION_COMPLETE: u8 = 0x04;
 const VEND_CASH_SALE: u8 = 0x05;
 const NEGATIVE_VEND_REQUEST: u8 = 0x06;
+//2019 L3 additions
+const VEND_REMOTE_VEND_DENIED: u8 = 0x07;
+const VEND_BASKET_REQUEST: u8 = 0x08;
+const VEND_BASKET_RESULT: u8 = 0x09;
+const VEND_COUPON_REPLY: u8 = 0x0A;
+const COUPON_ACCEPTED: u8 = 0x00;
+const COUPON_REJECTED: u8 = 0x01;
+const VEND_ASK_BEGIN_SESSION: u8 = 0x0B;
 //Vend replies
 const VEND_REPLY_APPROVED: u8 = 0x05;
 const VEND_REPLY_DENIED: u8 = 0x06;
@@ -72,40 +88,361 @@ const VEND_REPLY_REVALUE_APPROVED: u8 = 0x0D;
 const VEND_REPLY_REVALUE_DENIED: u8 = 0x0E;
 const VEND_REPLY_REVALUE_LIMIT_AMOUNT: u8 = 0x0F;
 
-//Some multi byte pre-written message to send to device
-//Breakdown - VMC level 3, display with no rows, no columns (none which we will
-//share with the contactless device, anyway!)
+//Expansion commands
+const EXPANSION_PREFIX: u8 = 0x17;
+const EXPANSION_REQUEST_ID: u8 = 0x00;
+const EXPANSION_WRITE_TIME_DATE: u8 = 0x03;
+//Expansion optional feature enable - followed by 4 bytes of feature bits
+const EXPANSION_FEATURE_ENABLE: u8 = 0x04;
+
+//L3 optional feature bits, as reported in the expansion ID reply and sent in feature enable
+const FEATURE_FTL: u32 = 0x0001;
+const FEATURE_32_BIT_MONETARY_FORMAT: u32 = 0x0002;
+const FEATURE_MULTICURRENCY: u32 = 0x0004;
+const FEATURE_NEGATIVE_VEND: u32 = 0x0008;
+const FEATURE_DATA_ENTRY: u32 = 0x0010;
+const FEATURE_ALWAYS_IDLE: u32 = 0x0020;
+//2019 additions
+const FEATURE_REMOTE_VEND: u32 = 0x0040;
+const FEATURE_BASKET: u32 = 0x0080;
+const FEATURE_COUPON: u32 = 0x0100;
+const FEATURE_ASK_BEGIN_SESSION: u32 = 0x0200;
+const FEATURE_ENHANCED_ITEM_NUMBER_INFORMATION: u32 = 0x0400;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub enum CashlessDeviceFeatureLevel {
+    Level1 = 0x01,
+    Level2 = 0x02,
+    Level3 = 0x03,
+}
+
+//Reader states from the spec.  The VMC tracks these so it can refuse commands
+//the reader would reject as out of sequence.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Format)]
+pub enum SessionState {
+    Inactive,     //Reset - needs init
+    Disabled,
+    Enabled,      //Waiting for a card
+    SessionIdle,  //Card present, funds available
+    Vend,         //Vend requested, until vend success/failure
+    Revalue,      //Revalue requested, until approved/denied
+    NegativeVend, //Negative vend requested, until vend success/failure
+}
+
+#[derive(Copy, Clone, Format)]
+pub enum VmcDisplayType {
+    Basic = 0x00,     //Numbers, upper case letters, blank and decimal point
+    FullAscii = 0x01,
+}
+
+//This is how we identify ourself to the cashless device in the expansion request ID.
+//Payment processors use this to attribute transactions to the machine, so it should be unique.
+//Text fields are space padded (or truncated) to the lengths in the spec.
+#[derive(Copy, Clone)]
+pub struct VmcIdentity {
+    pub manufacturer_code: str4, //3 chars
+    pub serial_number: str16,    //12 chars
+    pub model_number: str16,     //12 chars
+    pub software_version: u16,   //Packed BCD, eg 0x0102 for version 1.02
+}
+
+impl Default for VmcIdentity {
+    fn default() -> Self {
+        Self {
+            manufacturer_code: str4::from("DMP"),
+            serial_number: str16::from("000000000001"),
+            model_number: str16::from("000000000001"),
+            software_version: 0x0001,
+        }
+    }
+}
+
+impl VmcIdentity {
+    /// Builds the expansion request ID command
+    fn request_id_message(&self) -> [u8; 31] {
+        let mut msg: [u8; 31] = [b' '; 31];
+        msg[0] = EXPANSION_PREFIX;
+        msg[1] = EXPANSION_REQUEST_ID;
+        let fields: [(&[u8], usize, usize); 3] = [
+            (self.manufacturer_code.as_bytes(), 2, 3),
+            (self.serial_number.as_bytes(), 5, 12),
+            (self.model_number.as_bytes(), 17, 12),
+        ];
+        for (bytes, offset, field_len) in fields {
+            let len = bytes.len().min(field_len);
+            msg[offset..offset + len].copy_from_slice(&bytes[..len]);
+        }
+        msg[29..31].copy_from_slice(&self.software_version.to_be_bytes());
+        msg
+    }
+}
+
+//Time and date to send to the reader, eg for transaction timestamps
+#[derive(Copy, Clone, Format)]
+pub struct DateTime {
+    pub year: u16, //Only the last two digits are sent
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub day_of_week: u8, //1 = Monday ... 7 = Sunday
+    pub week_number: u8,
+    pub summertime: bool,
+    pub holiday: bool,
+}
+
+impl DateTime {
+    /// Builds the expansion write time/date command - every field is sent as BCD
+    fn write_time_date_message(&self) -> [u8; 12] {
+        fn bcd(value: u8) -> u8 {
+            (value / 10) << 4 | (value % 10)
+        }
+        [
+            EXPANSION_PREFIX,
+            EXPANSION_WRITE_TIME_DATE,
+            bcd((self.year % 100) as u8),
+            bcd(self.month),
+            bcd(self.day),
+            bcd(self.hour),
+            bcd(self.minute),
+            bcd(self.second),
+            bcd(self.day_of_week),
+            bcd(self.week_number),
+            self.summertime as u8,
+            self.holiday as u8,
+        ]
+    }
+}
+
+//Level 3 optional features - used both for what a reader supports, and what we've enabled
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Format)]
+pub struct L3Options {
+    pub ftl: bool,
+    pub monetary_format_32_bit: bool,
+    pub multicurrency: bool,
+    pub negative_vend: bool,
+    pub data_entry: bool,
+    pub always_idle: bool,
+    //2019 added new l3 features
+    pub remote_vend: bool,
+    pub basket: bool,
+    pub coupon: bool,
+    pub ask_begin_session: bool,
+    pub enhanced_item_number_information: bool,
+}
+
+impl L3Options {
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            ftl: bits & FEATURE_FTL != 0,
+            monetary_format_32_bit: bits & FEATURE_32_BIT_MONETARY_FORMAT != 0,
+            multicurrency: bits & FEATURE_MULTICURRENCY != 0,
+            negative_vend: bits & FEATURE_NEGATIVE_VEND != 0,
+            data_entry: bits & FEATURE_DATA_ENTRY != 0,
+            always_idle: bits & FEATURE_ALWAYS_IDLE != 0,
+            remote_vend: bits & FEATURE_REMOTE_VEND != 0,
+            basket: bits & FEATURE_BASKET != 0,
+            coupon: bits & FEATURE_COUPON != 0,
+            ask_begin_session: bits & FEATURE_ASK_BEGIN_SESSION != 0,
+            enhanced_item_number_information: bits & FEATURE_ENHANCED_ITEM_NUMBER_INFORMATION != 0,
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        let flags = [
+            (self.ftl, FEATURE_FTL),
+            (self.monetary_format_32_bit, FEATURE_32_BIT_MONETARY_FORMAT),
+            (self.multicurrency, FEATURE_MULTICURRENCY),
+            (self.negative_vend, FEATURE_NEGATIVE_VEND),
+            (self.data_entry, FEATURE_DATA_ENTRY),
+            (self.always_idle, FEATURE_ALWAYS_IDLE),
+            (self.remote_vend, FEATURE_REMOTE_VEND),
+            (self.basket, FEATURE_BASKET),
+            (self.coupon, FEATURE_COUPON),
+            (self.ask_begin_session, FEATURE_ASK_BEGIN_SESSION),
+            (self.enhanced_item_number_information, FEATURE_ENHANCED_ITEM_NUMBER_INFORMATION),
+        ];
+        flags.iter().filter(|(set, _)| *set).fold(0, |bits, (_, bit)| bits | bit)
+    }
+
+    /// Options present in both sets
+    pub fn intersection(&self, other: &L3Options) -> Self {
+        Self::from_bits(self.bits() & other.bits())
+    }
+}
+
+//What we tell the reader about ourselves during init
 //NB infuriatingly, the number of rows and columns we specify *changes* the length of the data
 //in one of the poll replies (0x02 - "Display Request" - where the number of bytes must equal rows*cols!)
-const VMC_SETUP_DATA: [u8; 6] = [0x11, 0x00, 0x03, 0x00, 0x00, 0x00];
+#[derive(Copy, Clone)]
+pub struct VmcConfig {
+    pub feature_level: CashlessDeviceFeatureLevel,
+    pub display_columns: u8,
+    pub display_rows: u8,
+    pub display_type: VmcDisplayType,
+    //Unscaled prices - u32::MAX max and 0 min mean "don't know"
+    pub max_price: u32,
+    pub min_price: u32,
+    pub identity: VmcIdentity,
+    //L3 options we'd like - only those the reader also supports get enabled
+    pub requested_options: L3Options,
+    //If set, TimeDateRequest poll events are answered automatically with the time from here
+    pub time_source: Option<fn() -> DateTime>,
+    //If set, check_session_timeout ends sessions left idle for this long
+    pub session_timeout: Option<Duration>,
+    //Approvals this refuses (including basket approvals) are failed straight away, poll reports
+    //them as VendDenied, and the vend's record is left in CashlessDevice::refused_vend
+    pub partial_approval_policy: PartialApprovalPolicy,
+}
 
-//Max and min prices set as "dont know"
-const VMC_MAX_MIN_PRICE_DATA: [u8; 6] = [0x11, 0x01, 0xFF, 0xFF, 0x00, 0x00];
+impl Default for VmcConfig {
+    //Level 3, no display (none which we will share with the contactless device, anyway!)
+    //and "don't know" prices
+    fn default() -> Self {
+        Self {
+            feature_level: CashlessDeviceFeatureLevel::Level3,
+            display_columns: 0,
+            display_rows: 0,
+            display_type: VmcDisplayType::Basic,
+            max_price: u32::MAX,
+            min_price: 0,
+            identity: VmcIdentity::default(),
+            requested_options: L3Options {
+                monetary_format_32_bit: true,
+                multicurrency: true,
+                always_idle: true,
+                ..L3Options::default()
+            },
+            time_source: None,
+            session_timeout: None,
+            partial_approval_policy: PartialApprovalPolicy::AcceptDiscount,
+        }
+    }
+}
 
-//This is how we identify ourself to the cashless device
-const VMC_EXPANSION_REQUEST_ID_DATA: [u8; 31] = [
-    0x17, 0x00, b'D', b'M', b'P', //Manufacturer ID
-    b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'1', //Serial number
-    b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'1', //Model number
-    b'0', b'1', //Software version
-];
+impl VmcConfig {
+    pub fn with_feature_level(mut self, feature_level: CashlessDeviceFeatureLevel) -> Self {
+        self.feature_level = feature_level;
+        self
+    }
 
-//This enables the 'always idle' feature only
-const FEATURE_FLAG_DATA: [u8; 6] = [0x17, 0x04, 0x00, 0x00, 0x00, 0x20];
+    pub fn with_display(mut self, columns: u8, rows: u8, display_type: VmcDisplayType) -> Self {
+        self.display_columns = columns;
+        self.display_rows = rows;
+        self.display_type = display_type;
+        self
+    }
 
-#[derive(Format)]
-pub enum CashlessDeviceFeatureLevel {
-    Level1,
-    Level2,
-    Level3,
+    pub fn with_prices(mut self, max_price: u32, min_price: u32) -> Self {
+        self.max_price = max_price;
+        self.min_price = min_price;
+        self
+    }
+
+    pub fn with_identity(mut self, identity: VmcIdentity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    pub fn with_requested_options(mut self, requested_options: L3Options) -> Self {
+        self.requested_options = requested_options;
+        self
+    }
+
+    pub fn with_time_source(mut self, time_source: fn() -> DateTime) -> Self {
+        self.time_source = Some(time_source);
+        self
+    }
+
+    pub fn with_partial_approval_policy(mut self, policy: PartialApprovalPolicy) -> Self {
+        self.partial_approval_policy = policy;
+        self
+    }
+
+    pub fn with_session_timeout(mut self, session_timeout: Duration) -> Self {
+        self.session_timeout = Some(session_timeout);
+        self
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct BeginSessionAdvancedData {
-    funds_available: u16,
+    funds_available: u32,
     payment_media_id: u32,
     payment_type: u8,
     payment_data: u16,
+    //Only present in expanded currency mode
+    user_language: Option<u16>,
+    user_currency_code: Option<u16>,
+    card_options: Option<u8>,
+}
+
+//Top two bits of the begin session payment type
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PaymentMediaType {
+    NormalVendCard,
+    TestMedia,
+    FreeVendCard,
+    Reserved,
+}
+
+impl BeginSessionAdvancedData {
+    pub fn funds_available(&self) -> u32 {
+        self.funds_available
+    }
+
+    /// Card/account number - 0 if the reader doesn't report one
+    pub fn payment_media_id(&self) -> u32 {
+        self.payment_media_id
+    }
+
+    pub fn payment_media_type(&self) -> PaymentMediaType {
+        match self.payment_type >> 6 {
+            0b00 => PaymentMediaType::NormalVendCard,
+            0b01 => PaymentMediaType::TestMedia,
+            0b10 => PaymentMediaType::FreeVendCard,
+            _ => PaymentMediaType::Reserved,
+        }
+    }
+
+    /// Raw payment type byte, for readers with their own uses for the low bits
+    pub fn payment_type(&self) -> u8 {
+        self.payment_type
+    }
+
+    /// User group for group pricing - the first byte of the payment data
+    pub fn user_group(&self) -> u8 {
+        (self.payment_data >> 8) as u8
+    }
+
+    /// Price list the user should be charged from - the second byte of the payment data
+    pub fn price_list(&self) -> u8 {
+        self.payment_data as u8
+    }
+
+    /// Whether the card can take a refund (negative vend) - None unless the reader is in
+    /// expanded currency mode
+    pub fn refunds_allowed(&self) -> Option<bool> {
+        self.card_options.map(|options| options & 0x01 != 0)
+    }
+
+    /// Whether value can be added to the card - None unless the reader is in expanded currency mode
+    pub fn revalue_allowed(&self) -> Option<bool> {
+        self.card_options.map(|options| options & 0x02 != 0)
+    }
+
+    /// The user's preferred language (ISO 639, two ASCII chars) - None unless the reader is in
+    /// expanded currency mode
+    pub fn user_language(&self) -> Option<u16> {
+        self.user_language
+    }
+
+    /// Currency the funds are held in - None unless the reader is in expanded currency mode
+    /// (the funds are then in the reader's own currency)
+    pub fn currency_code(&self) -> Option<u16> {
+        self.user_currency_code
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -125,33 +462,271 @@ pub enum MalfunctionCode {
     Unassigned,
 }
 
+//Reader wants a message shown on the VMC display, eg "Present card" or "Declined".
+//Text is rows*columns characters, row by row, for the display size given in VmcConfig.
+#[derive(Copy, Clone, Debug)]
+pub struct DisplayRequest {
+    pub display_time: u8, //In 0.1 second units
+    text: [u8; MAX_DISPLAY_CHARS],
+    len: u8,
+}
+
+impl DisplayRequest {
+    pub fn text(&self) -> &[u8] {
+        &self.text[..self.len as usize]
+    }
+
+    /// One row of the message, given the display's column count
+    pub fn row(&self, row: usize, columns: usize) -> Option<&[u8]> {
+        self.text().chunks(columns.max(1)).nth(row)
+    }
+}
+
+//A customer picked an item in a mobile app, and the reader wants us to vend it
+#[derive(Copy, Clone, Debug)]
+pub struct RemoteVendRequest {
+    pub price: u32, //unscaled amount
+    pub item_number: u16,
+}
+
+//Product details sent alongside the item number in vend request, vend success and cash sale
+//when enhanced item number information is enabled - request it in VmcConfig, as it changes
+//the length of those commands.  Text is sent space padded.
+#[derive(Copy, Clone, Debug)]
+pub struct ItemDescription {
+    pub item_number: u16,
+    pub product_code: str16, //8 chars, eg the planogram product code
+    pub description: str16,  //16 chars
+}
+
+const ITEM_PRODUCT_CODE_LEN: usize = 8;
+const ITEM_DESCRIPTION_LEN: usize = 16;
+const ITEM_INFO_LEN: usize = ITEM_PRODUCT_CODE_LEN + ITEM_DESCRIPTION_LEN;
+
+impl ItemDescription {
+    pub fn new(item_number: u16, product_code: &str, description: &str) -> Self {
+        Self {
+            item_number,
+            product_code: str16::from(product_code),
+            description: str16::from(description),
+        }
+    }
+
+    fn write_info(&self, buf: &mut [u8]) {
+        buf[..ITEM_INFO_LEN].fill(b' ');
+        let fields: [(&[u8], usize, usize); 2] = [
+            (self.product_code.as_bytes(), 0, ITEM_PRODUCT_CODE_LEN),
+            (self.description.as_bytes(), ITEM_PRODUCT_CODE_LEN, ITEM_DESCRIPTION_LEN),
+        ];
+        for (bytes, offset, field_len) in fields {
+            let len = bytes.len().min(field_len);
+            buf[offset..offset + len].copy_from_slice(&bytes[..len]);
+        }
+    }
+}
+
+//Most items in one basket vend - the result is reported as a one byte mask.
+//In expanded currency mode only 5 fit in the 36 byte MDB message.
+pub const MAX_BASKET_ITEMS: usize = 8;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BasketItem {
+    pub price: u32, //unscaled amount
+    pub item_number: u16,
+}
+
+//Reader's answer to a basket vend request - bit n of approved_items set if item n was approved
+#[derive(Copy, Clone, Debug)]
+pub struct BasketApproval {
+    pub amount: u32, //unscaled amount
+    pub approved_items: u8,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CouponType {
+    AmountOff,  //Value is an unscaled amount
+    PercentOff, //Value is a percentage
+    FreeVend,
+    Other(u8),
+}
+
+//Reader scanned a coupon - accept or reject it with coupon_reply
+#[derive(Copy, Clone, Debug)]
+pub struct Coupon {
+    pub coupon_type: CouponType,
+    pub value: u32,
+}
+
+impl Coupon {
+    /// What an item priced at price costs once this coupon is applied
+    pub fn discounted_price(&self, price: u32) -> u32 {
+        match self.coupon_type {
+            CouponType::AmountOff => price.saturating_sub(self.value),
+            CouponType::PercentOff => {
+                let discount = (price as u64 * self.value.min(100) as u64 / 100) as u32;
+                price - discount
+            }
+            CouponType::FreeVend => 0,
+            CouponType::Other(_) => price,
+        }
+    }
+}
+
+//Most keys a data entry response can carry - two to a byte in its 8 byte data field
+pub const MAX_DATA_ENTRY_KEYS: usize = 16;
+
+//Reader wants the customer to type something (eg a PIN) on the machine keypad
+#[derive(Copy, Clone, Debug)]
+pub struct DataEntryRequest {
+    pub length: u8,   //Number of keypresses wanted, 1 to MAX_DATA_ENTRY_KEYS
+    pub repeat: bool, //Ask the customer to enter it twice (eg a new PIN)
+}
+
+//Reader config data - the reply to SETUP CONFIG DATA, or sent unsolicited in a poll
+#[derive(Copy, Clone, Debug)]
+pub struct ReaderConfigData {
+    pub feature_level: CashlessDeviceFeatureLevel, //The reader's own level, not the one we operate at
+    pub country_code: u16,
+    pub scale_factor: u8,
+    pub decimal_places: u8,
+    pub max_response_time: u8,
+    pub can_restore_funds: bool,
+    pub multivend_capable: bool,
+    pub has_display: bool,
+    pub supports_cash_sale_cmd: bool,
+}
+
+impl ReaderConfigData {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 8 || bytes[0] != SETUP_REPLY_READER_CONFIG_DATA {
+            return None;
+        }
+        Some(Self {
+            feature_level: match bytes[1] {
+                0x02 => CashlessDeviceFeatureLevel::Level2,
+                0x03 => CashlessDeviceFeatureLevel::Level3,
+                _ => CashlessDeviceFeatureLevel::Level1,
+            },
+            country_code: u16::from_be_bytes([bytes[2], bytes[3]]),
+            scale_factor: bytes[4],
+            decimal_places: bytes[5],
+            max_response_time: bytes[6],
+            //Optional feature flags
+            can_restore_funds: bytes[7] & 0x01 != 0,
+            multivend_capable: bytes[7] & 0x02 != 0,
+            has_display: bytes[7] & 0x04 != 0,
+            supports_cash_sale_cmd: bytes[7] & 0x08 != 0,
+        })
+    }
+}
+
+//Peripheral ID - the reply to EXPANSION REQUEST ID, or sent unsolicited in a poll.
+//L3 readers talking to an L3 VMC append their option bits.
+#[derive(Copy, Clone, Debug)]
+pub struct PeripheralId {
+    pub manufacturer_code: str4,
+    pub serial_number: str16,
+    pub model_number: str16,
+    pub software_version: str4,
+    pub options: Option<L3Options>,
+}
+
+impl PeripheralId {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.first() != Some(&POLL_REPLY_PERIPHERAL_ID) {
+            return None;
+        }
+        let options = match bytes.len() {
+            30 => None,
+            34 => Some(L3Options::from_bits(u32::from_be_bytes([bytes[30], bytes[31], bytes[32], bytes[33]]))),
+            _ => return None,
+        };
+        Some(Self {
+            manufacturer_code: str4::from(ascii_field(&bytes[1..4])),
+            serial_number: str16::from(ascii_field(&bytes[4..16])),
+            model_number: str16::from(ascii_field(&bytes[16..28])),
+            software_version: str4::from(ascii_field(&bytes[28..30])),
+            options,
+        })
+    }
+}
+
+fn ascii_field(bytes: &[u8]) -> &str {
+    match from_utf8(bytes) {
+        Ok(a) => a,
+        Err(_) => {
+            error!("Non-ascii text in peripheral ID");
+            ""
+        }
+    }
+}
+
 //A poll event might be one of the following:
 #[derive(Copy, Clone, Debug)]
 pub enum PollEvent {
     JustReset,
-    ReaderConfigData,
-    BeginSessionLevelBasic(u16), //'scaled' funds
+    ReaderConfigData(ReaderConfigData),
+    DisplayRequest(DisplayRequest),
+    BeginSessionLevelBasic(u32), //'scaled' funds
     BeginSessionLevelAdvanced(BeginSessionAdvancedData),
     SessionCancelRequest,
-    VendApproved(u16), //unscaled amount
+    VendApproved(u32), //unscaled amount
     VendDenied,
     EndSession,
     Cancelled,
-    PeripheralId, //We are a level 3 VMC, so we will parse...
+    PeripheralId(PeripheralId),
     Malfunction(MalfunctionCode),
-    CmdOutOfSequence, //If VMC level 3, then there'll be an optional status byte here
+    CmdOutOfSequence(Option<SessionState>), //L2/3 readers report the state they are really in
     RevalueApproved,
     RevalueDenied,
-    RevalueLimitAmount(u16),
+    RevalueLimitAmount(u32),
     UserFileData,
-    TimeDateRequest,
-    DataEntryRequest,
+    TimeDateRequest, //Already answered if VmcConfig has a time source
+    DataEntryRequest(DataEntryRequest),
+    DataEntryCancel,
+    RemoteVendRequest(RemoteVendRequest),
+    BasketApproved(BasketApproval),
+    CouponReport(Coupon),
+    BeginSessionRefused, //Reader won't start the session we asked for
     //Unimplemented:
-    //DisplayRequest - we don't have a display
     //UserFileData (obsolete)
-    //TimeDateRequest
-    //?  SelectionRequest,
-    //?  CouponReport,
+}
+
+//The reader may answer a vend command straight away, or ACK it and report the result in a later poll
+#[derive(Copy, Clone, Debug)]
+pub enum VendOutcome {
+    Pending, //ACKd - the result will arrive as a poll event
+    Approved(u32),
+    BasketApproved(BasketApproval),
+    Denied, //Including approvals refused under the partial approval policy
+}
+
+//What to do when the reader approves a different amount to the price we asked for
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Format)]
+pub enum PartialApprovalPolicy {
+    AcceptAny,      //Vend at whatever amount the reader approved
+    AcceptDiscount, //Vend if the approved amount is no more than the price, otherwise refuse it
+    ExactOnly,      //Refuse any approval that isn't for the price
+}
+
+//Requested, approved and charged amounts for one vend - for the audit trail.
+//For negative vends, charged is the amount credited back to the card.
+#[derive(Copy, Clone, Debug)]
+pub struct VendRecord {
+    pub list_price: u32,
+    pub coupon: Option<Coupon>, //Coupon applied to the list price, if any
+    pub requested: u32,         //What we asked the reader for, after any coupon
+    pub approved: Option<u32>, //None if the reader never approved it
+    pub charged: u32,
+}
+
+//The reader may answer a revalue command straight away, or ACK it and report the result in a later poll
+#[derive(Copy, Clone, Debug)]
+pub enum RevalueOutcome {
+    Pending, //ACKd - the result will arrive as a poll event
+    Approved,
+    Denied,
+    LimitAmount(u32), //The most that can be added to the card
 }
 
 pub enum PollError {
@@ -164,22 +739,52 @@ impl TryFrom<&[u8]> for PollEvent {
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
         match bytes[0] {
             POLL_REPLY_JUST_RESET => Ok(PollEvent::JustReset),
-            POLL_REPLY_READER_CONFIG_DATA => Ok(PollEvent::ReaderConfigData),
-            POLL_REPLY_DISPLAY_REQUEST => Err(PollError::UnsupportedEvent),
+            POLL_REPLY_READER_CONFIG_DATA => ReaderConfigData::parse(bytes)
+                .map(PollEvent::ReaderConfigData)
+                .ok_or(PollError::InvalidEvent),
+            POLL_REPLY_DISPLAY_REQUEST => {
+                if bytes.len() < 2 || bytes.len() - 2 > MAX_DISPLAY_CHARS {
+                    return Err(PollError::InvalidEvent);
+                }
+                let mut text: [u8; MAX_DISPLAY_CHARS] = [b' '; MAX_DISPLAY_CHARS];
+                text[..bytes.len() - 2].copy_from_slice(&bytes[2..]);
+                Ok(PollEvent::DisplayRequest(DisplayRequest {
+                    display_time: bytes[1],
+                    text,
+                    len: (bytes.len() - 2) as u8,
+                }))
+            }
             POLL_REPLY_BEGIN_SESSION => {
                 match bytes.len() {
                     3 => {
                         //Level 1 reader
-                        Ok(PollEvent::BeginSessionLevelBasic(u16::from_le_bytes([bytes[2], bytes[1]])))
+                        Ok(PollEvent::BeginSessionLevelBasic(u16::from_be_bytes([bytes[1], bytes[2]]) as u32))
                     }
                     10 => {
                         //Level 2/3 reader
                         Ok(PollEvent::BeginSessionLevelAdvanced(
                             BeginSessionAdvancedData {
-                                funds_available: u16::from_le_bytes([bytes[2], bytes[1]]),
-                                payment_media_id: u32::from_le_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]),
+                                funds_available: u16::from_be_bytes([bytes[1], bytes[2]]) as u32,
+                                payment_media_id: u32::from_be_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]),
                                 payment_type: bytes[7],
-                                payment_data: u16::from_le_bytes([bytes[9], bytes[8]]),
+                                payment_data: u16::from_be_bytes([bytes[8], bytes[9]]),
+                                user_language: None,
+                                user_currency_code: None,
+                                card_options: None,
+                            },
+                        ))
+                    }
+                    17 => {
+                        //Level 3 reader in expanded currency mode - 32 bit funds
+                        Ok(PollEvent::BeginSessionLevelAdvanced(
+                            BeginSessionAdvancedData {
+                                funds_available: u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+                                payment_media_id: u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]),
+                                payment_type: bytes[9],
+                                payment_data: u16::from_be_bytes([bytes[10], bytes[11]]),
+                                user_language: Some(u16::from_be_bytes([bytes[12], bytes[13]])),
+                                user_currency_code: Some(u16::from_be_bytes([bytes[14], bytes[15]])),
+                                card_options: Some(bytes[16]),
                             },
                         ))
                     }
@@ -192,14 +797,20 @@ impl TryFrom<&[u8]> for PollEvent {
             POLL_REPLY_SESSION_CANCEL_REQUEST => Ok(PollEvent::SessionCancelRequest),
             POLL_REPLY_VEND_APPROVED => match bytes.len() {
                 3 => {
-                    Ok(PollEvent::VendApproved(u16::from_le_bytes([bytes[2], bytes[1]])))
+                    Ok(PollEvent::VendApproved(u16::from_be_bytes([bytes[1], bytes[2]]) as u32))
+                },
+                5 => {
+                    //Expanded currency mode
+                    Ok(PollEvent::VendApproved(u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]])))
                 },
                 _ => Err(PollError::InvalidEvent),
             },
             POLL_REPLY_VEND_DENIED => Ok(PollEvent::VendDenied),
             POLL_REPLY_END_SESSION => Ok(PollEvent::EndSession),
             POLL_REPLY_CANCELLED => Ok(PollEvent::Cancelled),
-            POLL_REPLY_PERIPHERAL_ID => Ok(PollEvent::PeripheralId),
+            POLL_REPLY_PERIPHERAL_ID => PeripheralId::parse(bytes)
+                .map(PollEvent::PeripheralId)
+                .ok_or(PollError::InvalidEvent),
             POLL_REPLY_MALFUNCTION => {
                 match bytes.len() {
                     2 => match bytes[1] {
@@ -221,18 +832,87 @@ impl TryFrom<&[u8]> for PollEvent {
                     _ => Err(PollError::InvalidEvent),
                 }
             }
-            POLL_REPLY_OUT_OF_SEQUENCE => Ok(PollEvent::CmdOutOfSequence),
+            POLL_REPLY_OUT_OF_SEQUENCE => match bytes.len() {
+                1 => Ok(PollEvent::CmdOutOfSequence(None)),
+                2 => Ok(PollEvent::CmdOutOfSequence(match bytes[1] {
+                    0x01 => Some(SessionState::Inactive),
+                    0x02 => Some(SessionState::Disabled),
+                    0x03 => Some(SessionState::Enabled),
+                    _ => None,
+                })),
+                _ => Err(PollError::InvalidEvent),
+            },
             POLL_REPLY_REVALUE_APPROVED => Ok(PollEvent::RevalueApproved),
+            POLL_REPLY_REVALUE_DENIED => Ok(PollEvent::RevalueDenied),
+            POLL_REPLY_REVALUE_LIMIT_AMOUNT => match bytes.len() {
+                3 => Ok(PollEvent::RevalueLimitAmount(u16::from_be_bytes([bytes[1], bytes[2]]) as u32)),
+                //Expanded currency mode
+                5 => Ok(PollEvent::RevalueLimitAmount(u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]))),
+                _ => Err(PollError::InvalidEvent),
+            },
             POLL_REPLY_USER_FILE_DATA => Ok(PollEvent::UserFileData),
             POLL_REPLY_TIME_DATE_REQUEST => Ok(PollEvent::TimeDateRequest),
-            POLL_REPLY_DATA_ENTRY_REQUEST => Ok(PollEvent::DataEntryRequest),
+            POLL_REPLY_DATA_ENTRY_REQUEST => match bytes.len() {
+                //Top bit is the repeat flag, the rest is the length - which has to fit in the response
+                2 if (1..=MAX_DATA_ENTRY_KEYS).contains(&((bytes[1] & 0x7F) as usize)) => {
+                    Ok(PollEvent::DataEntryRequest(DataEntryRequest {
+                        length: bytes[1] & 0x7F,
+                        repeat: bytes[1] & 0x80 != 0,
+                    }))
+                }
+                _ => Err(PollError::InvalidEvent),
+            },
+            POLL_REQUEST_DATA_ENTRY_CANCEL => Ok(PollEvent::DataEntryCancel),
+            POLL_REPLY_BEGIN_SESSION_REFUSED => Ok(PollEvent::BeginSessionRefused),
+            POLL_REPLY_BASKET_APPROVED => match bytes.len() {
+                4 => Ok(PollEvent::BasketApproved(BasketApproval {
+                    amount: u16::from_be_bytes([bytes[1], bytes[2]]) as u32,
+                    approved_items: bytes[3],
+                })),
+                //Expanded currency mode
+                6 => Ok(PollEvent::BasketApproved(BasketApproval {
+                    amount: u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+                    approved_items: bytes[5],
+                })),
+                _ => Err(PollError::InvalidEvent),
+            },
+            POLL_REPLY_COUPON_REPORT => {
+                let value = match bytes.len() {
+                    4 => u16::from_be_bytes([bytes[2], bytes[3]]) as u32,
+                    //Expanded currency mode
+                    6 => u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]),
+                    _ => return Err(PollError::InvalidEvent),
+                };
+                let coupon_type = match bytes[1] {
+                    0x00 => CouponType::AmountOff,
+                    0x01 => CouponType::PercentOff,
+                    0x02 => CouponType::FreeVend,
+                    x => CouponType::Other(x),
+                };
+                Ok(PollEvent::CouponReport(Coupon { coupon_type, value }))
+            }
+            POLL_REPLY_REMOTE_VEND_REQUEST => match bytes.len() {
+                //Price then item number, as in VEND REQUEST
+                5 => Ok(PollEvent::RemoteVendRequest(RemoteVendRequest {
+                    price: u16::from_be_bytes([bytes[1], bytes[2]]) as u32,
+                    item_number: u16::from_be_bytes([bytes[3], bytes[4]]),
+                })),
+                //Expanded currency mode
+                7 => Ok(PollEvent::RemoteVendRequest(RemoteVendRequest {
+                    price: u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+                    item_number: u16::from_be_bytes([bytes[5], bytes[6]]),
+                })),
+                _ => Err(PollError::InvalidEvent),
+            },
             _ => Err(PollError::InvalidEvent),
         }
     }
 }
 
 pub struct CashlessDevice {
+    //The level we're operating at - the lower of the reader's and the VMC's
     pub feature_level: CashlessDeviceFeatureLevel,
+    pub vmc_config: VmcConfig,
     pub country_code: u16,
     pub scale_factor: u8,
     pub decimal_places: u8,
@@ -248,22 +928,439 @@ pub struct CashlessDevice {
     pub model_number: str16,
     pub software_version: str4,
 
-    //Level 3 features
-    pub supports_ftl: bool,
-    pub monetary_format_32_bit: bool,
-    pub supports_multicurrency: bool,
-    pub supports_negative_vend: bool,
-    pub supports_data_entry: bool,
-    pub supports_always_idle: bool,
-    //2019 added new l3 features
-    pub supports_remote_vend: bool,
-    pub supports_basket: bool,
-    pub supports_coupon: bool,
-    pub supports_ask_begin_session: bool,
-    pub supports_enhanced_item_number_information: bool,
-}
+    //Level 3 features the reader supports (none for L1-2 readers)
+    pub supported_options: L3Options,
+    state: SessionState,
+    //Reader went inactive while enabled - poll re-enables it once setup succeeds
+    reenable_after_setup: bool,
+    //Current session - funds left on the card (None if the reader doesn't know), and vends so far.
+    //Multivend capable readers allow several vends per session, others only one.
+    pub session_funds: Option<u32>,
+    pub vends_this_session: u8,
+    //Currency the reader reported in BEGIN SESSION (multicurrency mode only)
+    session_currency: Option<u16>,
+    //Current vend - what we asked for, and what the reader approved
+    list_price: Option<u32>,
+    vend_coupon: Option<Coupon>,
+    requested_amount: Option<u32>,
+    approved_amount: Option<u32>,
+    last_session_activity: Option<Instant>,
+    //Last vend poll refused under the partial approval policy, for the audit trail
+    pub refused_vend: Option<VendRecord>,
+    //Remote vend the reader asked for, awaiting accept/deny
+    pub pending_remote_vend: Option<RemoteVendRequest>,
+    //Data entry the reader asked for, awaiting the customer's keys
+    pub pending_data_entry: Option<DataEntryRequest>,
+    //The session only exists because of a remote vend request - the reader never began one
+    remote_vend_session: bool,
+    //Coupons - reported by the reader and awaiting our reply, then accepted and waiting for a vend
+    pending_coupon: Option<Coupon>,
+    pub accepted_coupon: Option<Coupon>,
+    //Basket vend in progress
+    basket: [BasketItem; MAX_BASKET_ITEMS],
+    basket_len: usize,
+    basket_approved_items: u8,
+
+    //Level 3 features we actually enabled - the driver behaves according to these.
+    //With 32 bit monetary format all amounts are 4 bytes on the wire.
+    //With multicurrency, cash sales and max/min prices carry a currency code, and BEGIN SESSION
+    //says which currency the session is in - this is only enabled alongside 32 bit monetary format,
+    //as the currency-carrying BEGIN SESSION is 32 bit.
+    pub enabled_options: L3Options,
+}
+
+impl CashlessDevice {
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    fn require_state(&self, allowed: &[SessionState]) -> Result<(), ()> {
+        if allowed.contains(&self.state) {
+            Ok(())
+        } else {
+            error!("Command not allowed in cashless state {}", self.state);
+            Err(())
+        }
+    }
+
+    /// Vends may start from session idle, or straight from enabled if the reader is always idle
+    fn require_vend_allowed(&self) -> Result<(), ()> {
+        if !self.multivend_capable && self.vends_this_session > 0 {
+            error!("Single vend reader - end the session before vending again");
+            return Err(());
+        }
+        if self.enabled_options.always_idle {
+            self.require_state(&[SessionState::Enabled, SessionState::SessionIdle])
+        } else {
+            self.require_state(&[SessionState::SessionIdle])
+        }
+    }
+
+    fn set_state(&mut self, state: SessionState) {
+        //A session we made up for a remote vend ends with the vend
+        let state = if state == SessionState::SessionIdle && self.state == SessionState::Vend && self.remote_vend_session {
+            SessionState::Enabled
+        } else {
+            state
+        };
+        if state == SessionState::Inactive && !matches!(self.state, SessionState::Inactive | SessionState::Disabled) {
+            //Reader dropped out while enabled - put it back once it has been set up again
+            self.reenable_after_setup = true;
+        }
+        if state != self.state {
+            debug!("Cashless state {} -> {}", self.state, state);
+            self.state = state;
+        }
+        match state {
+            SessionState::Inactive | SessionState::Disabled | SessionState::Enabled => {
+                //Out of session - forget it
+                self.session_funds = None;
+                self.vends_this_session = 0;
+                self.session_currency = None;
+                self.list_price = None;
+                self.vend_coupon = None;
+                self.requested_amount = None;
+                self.approved_amount = None;
+                self.last_session_activity = None;
+                self.pending_remote_vend = None;
+                self.remote_vend_session = false;
+                self.pending_data_entry = None;
+                self.pending_coupon = None;
+                self.accepted_coupon = None;
+            }
+            _ => self.last_session_activity = Some(Instant::now()),
+        }
+        //A basket vend ends with the vend, however it ends
+        if state != SessionState::Vend {
+            self.basket_len = 0;
+            self.basket_approved_items = 0;
+        }
+    }
+
+    /// Start tracking a new session's funds - all 1s means the reader doesn't know
+    fn begin_session(&mut self, funds: u32, funds_unknown: u32) {
+        self.set_session_funds(funds, funds_unknown);
+        self.vends_this_session = 0;
+        self.list_price = None;
+        self.vend_coupon = None;
+        self.requested_amount = None;
+        self.approved_amount = None;
+    }
+
+    fn set_session_funds(&mut self, funds: u32, funds_unknown: u32) {
+        self.session_funds = if funds == funds_unknown { None } else { Some(funds) };
+    }
+
+    /// A vend (or negative vend) has finished - update the session's funds.
+    /// Never charges more than max_charge, nor more than was approved.
+    fn complete_vend(&mut self, success: bool, max_charge: u32) -> VendRecord {
+        let record = self.take_vend_record(if success { max_charge } else { 0 });
+        //A coupon is only used up by a vend that went through - otherwise it's kept for the next
+        if success && record.coupon.is_some() {
+            self.accepted_coupon = None;
+        }
+        self.session_funds = match self.state {
+            SessionState::NegativeVend => self.session_funds.map(|f| f.saturating_add(record.charged)),
+            _ => self.session_funds.map(|f| f.saturating_sub(record.charged)),
+        };
+        self.vends_this_session = self.vends_this_session.saturating_add(1);
+        debug!(
+            "Vend complete - requested {}, approved {}, charged {}",
+            record.requested, record.approved, record.charged
+        );
+        record
+    }
+
+    /// Remember what we asked for, for the record once the vend completes
+    fn start_vend_record(&mut self, list_price: u32, coupon: Option<Coupon>, requested: u32) {
+        self.list_price = Some(list_price);
+        self.vend_coupon = coupon;
+        self.requested_amount = Some(requested);
+        self.approved_amount = None;
+    }
+
+    fn take_vend_record(&mut self, max_charge: u32) -> VendRecord {
+        let approved = self.approved_amount.take();
+        let requested = self.requested_amount.take().unwrap_or(0);
+        VendRecord {
+            list_price: self.list_price.take().unwrap_or(requested),
+            coupon: self.vend_coupon.take(),
+            requested,
+            approved,
+            charged: approved.unwrap_or(0).min(max_charge),
+        }
+    }
+
+    //Tracking state for a reader that has just been set up, before any options are enabled
+    fn from_setup(
+        vmc_config: VmcConfig,
+        feature_level: CashlessDeviceFeatureLevel,
+        config: &ReaderConfigData,
+        id: PeripheralId,
+    ) -> Self {
+        CashlessDevice {
+            feature_level,
+            vmc_config,
+            country_code: config.country_code,
+            scale_factor: config.scale_factor,
+            decimal_places: config.decimal_places,
+            max_response_time: config.max_response_time,
+
+            //Basic option flags
+            can_restore_funds: config.can_restore_funds,
+            multivend_capable: config.multivend_capable,
+            has_display: config.has_display,
+            supports_cash_sale_cmd: config.supports_cash_sale_cmd,
+
+            //Data from the expansion request
+            manufacturer_code: id.manufacturer_code,
+            serial_number: id.serial_number,
+            model_number: id.model_number,
+            software_version: id.software_version,
+            //L1-2 readers wont support any of these
+            supported_options: id.options.unwrap_or_default(),
+            enabled_options: L3Options::default(),
+            //Setup done, so the reader is now disabled
+            state: SessionState::Disabled,
+            reenable_after_setup: false,
+            session_funds: None,
+            vends_this_session: 0,
+            session_currency: None,
+            list_price: None,
+            vend_coupon: None,
+            requested_amount: None,
+            approved_amount: None,
+            last_session_activity: None,
+            refused_vend: None,
+            pending_remote_vend: None,
+            pending_data_entry: None,
+            remote_vend_session: false,
+            pending_coupon: None,
+            accepted_coupon: None,
+            basket: [BasketItem::default(); MAX_BASKET_ITEMS],
+            basket_len: 0,
+            basket_approved_items: 0,
+        }
+    }
+
+    /// Whether the reader's approval of the current vend is acceptable under our policy
+    /// For a basket, the approved amount is compared with the price of the items approved -
+    /// and ExactOnly also refuses a basket with any item left out.
+    fn approval_acceptable(&self) -> bool {
+        let (requested, approved) = match (self.requested_amount, self.approved_amount) {
+            (Some(requested), Some(approved)) => (requested, approved),
+            _ => return true,
+        };
+        let (requested, all_items) = if self.basket_len > 0 {
+            let approved_price = self.basket[..self.basket_len]
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| self.basket_approved_items & (0x01 << i) != 0)
+                .fold(0u32, |total, (_, item)| total.saturating_add(item.price));
+            let all_items = (0..self.basket_len).all(|i| self.basket_approved_items & (0x01 << i) != 0);
+            (approved_price, all_items)
+        } else {
+            (requested, true)
+        };
+        match self.vmc_config.partial_approval_policy {
+            PartialApprovalPolicy::AcceptAny => true,
+            PartialApprovalPolicy::AcceptDiscount => approved <= requested,
+            PartialApprovalPolicy::ExactOnly => all_items && approved == requested,
+        }
+    }
+
+    /// Whether another vend can be requested in this session
+    pub fn can_vend_again(&self) -> bool {
+        self.state == SessionState::SessionIdle && (self.multivend_capable || self.vends_this_session == 0)
+    }
+
+    /// The reader has sent its config data again, eg after reconfiguration
+    fn apply_reader_config(&mut self, config: &ReaderConfigData) {
+        self.feature_level = core::cmp::min(config.feature_level, self.vmc_config.feature_level);
+        if self.feature_level != CashlessDeviceFeatureLevel::Level3 {
+            //Options are L3 only - and poll replies are back to their basic lengths
+            self.supported_options = L3Options::default();
+            self.enabled_options = L3Options::default();
+        }
+        self.country_code = config.country_code;
+        self.scale_factor = config.scale_factor;
+        self.decimal_places = config.decimal_places;
+        self.max_response_time = config.max_response_time;
+        self.can_restore_funds = config.can_restore_funds;
+        self.multivend_capable = config.multivend_capable;
+        self.has_display = config.has_display;
+        self.supports_cash_sale_cmd = config.supports_cash_sale_cmd;
+    }
+
+    /// The reader has sent its peripheral ID again - options it no longer supports are
+    /// treated as disabled
+    fn apply_peripheral_id(&mut self, id: &PeripheralId) {
+        self.manufacturer_code = id.manufacturer_code;
+        self.serial_number = id.serial_number;
+        self.model_number = id.model_number;
+        self.software_version = id.software_version;
+        self.supported_options = id.options.unwrap_or_default();
+        self.enabled_options = self.enabled_options.intersection(&self.supported_options);
+    }
+
+    /// Move the state machine on according to an event from the reader
+    fn apply_event(&mut self, event: &PollEvent) {
+        let in_session = matches!(
+            self.state,
+            SessionState::SessionIdle | SessionState::Vend | SessionState::Revalue | SessionState::NegativeVend
+        );
+        match event {
+            PollEvent::BeginSessionLevelBasic(funds) if self.remote_vend_session => {
+                //The reader has opened a real session after all - it stays open after the remote vend
+                self.remote_vend_session = false;
+                self.set_session_funds(*funds, u16::MAX as u32);
+            }
+            PollEvent::BeginSessionLevelAdvanced(data) if self.remote_vend_session => {
+                self.remote_vend_session = false;
+                let unknown = if self.enabled_options.monetary_format_32_bit { u32::MAX } else { u16::MAX as u32 };
+                self.set_session_funds(data.funds_available, unknown);
+            }
+            PollEvent::BeginSessionLevelBasic(funds) if self.state == SessionState::Enabled => {
+                self.begin_session(*funds, u16::MAX as u32);
+            }
+            PollEvent::BeginSessionLevelAdvanced(data) if self.state == SessionState::Enabled => {
+                let unknown = if self.enabled_options.monetary_format_32_bit { u32::MAX } else { u16::MAX as u32 };
+                self.begin_session(data.funds_available, unknown);
+                if self.enabled_options.multicurrency {
+                    self.session_currency = data.currency_code().filter(|&code| code != 0);
+                }
+            }
+            PollEvent::VendApproved(amount)
+                if matches!(self.state, SessionState::Vend | SessionState::NegativeVend) => {
+                self.approved_amount = Some(*amount);
+            }
+            PollEvent::BasketApproved(approval)
+                if self.state == SessionState::Vend && self.basket_len > 0 => {
+                self.approved_amount = Some(approval.amount);
+                self.basket_approved_items = approval.approved_items;
+            }
+            PollEvent::ReaderConfigData(config) => self.apply_reader_config(config),
+            PollEvent::PeripheralId(id) => self.apply_peripheral_id(id),
+            PollEvent::CouponReport(coupon) if self.enabled_options.coupon => {
+                self.pending_coupon = Some(*coupon);
+            }
+            PollEvent::RemoteVendRequest(request) if self.enabled_options.remote_vend => {
+                if self.state == SessionState::Enabled {
+                    //Funds are whatever the app user agreed to pay.  The reader hasn't really
+                    //begun a session, so this one only lasts for the remote vend.
+                    self.begin_session(request.price, u32::MAX);
+                    self.remote_vend_session = true;
+                }
+                self.pending_remote_vend = Some(*request);
+            }
+            PollEvent::DataEntryRequest(request) if self.enabled_options.data_entry => {
+                self.pending_data_entry = Some(*request);
+            }
+            PollEvent::DataEntryCancel => self.pending_data_entry = None,
+            _ => {}
+        }
+        let state = match event {
+            PollEvent::JustReset => SessionState::Inactive,
+            //The app user is effectively the card holder - this starts a session
+            PollEvent::RemoteVendRequest(_)
+                if self.state == SessionState::Enabled && self.enabled_options.remote_vend => SessionState::SessionIdle,
+            PollEvent::BeginSessionLevelBasic(_) | PollEvent::BeginSessionLevelAdvanced(_)
+                if self.state == SessionState::Enabled => SessionState::SessionIdle,
+            PollEvent::VendDenied
+                if matches!(self.state, SessionState::Vend | SessionState::NegativeVend) => SessionState::SessionIdle,
+            PollEvent::RevalueApproved | PollEvent::RevalueDenied
+                if self.state == SessionState::Revalue => SessionState::SessionIdle,
+            PollEvent::EndSession | PollEvent::Cancelled if in_session => SessionState::Enabled,
+            PollEvent::CmdOutOfSequence(reported) => {
+                //Resync with whatever the reader says it is doing (poll sets it up again if it says
+                //inactive).  If it can't tell us, assume any session is over.
+                warn!("Cashless device reports command out of sequence - resyncing");
+                match reported {
+                    Some(state) => *state,
+                    None if in_session => SessionState::Enabled,
+                    None => self.state,
+                }
+            }
+            _ => self.state,
+        };
+        self.set_state(state);
+    }
+
+    /// Number of bytes used for an amount on the wire - 4 in expanded currency mode, otherwise 2
+    fn amount_length(&self) -> usize {
+        if self.enabled_options.monetary_format_32_bit { 4 } else { 2 }
+    }
+
+    /// Writes amount (MSB first) into buf, returning the number of bytes used.
+    /// None if the amount won't fit in 16 bits and we're not in expanded currency mode.
+    fn write_amount(&self, amount: u32, buf: &mut [u8]) -> Option<usize> {
+        if self.enabled_options.monetary_format_32_bit {
+            buf[0..4].copy_from_slice(&amount.to_be_bytes());
+            Some(4)
+        } else {
+            let amount = u16::try_from(amount).ok()?;
+            buf[0..2].copy_from_slice(&amount.to_be_bytes());
+            Some(2)
+        }
+    }
+
+    /// Currency vends are priced in - VEND REQUEST has no currency, so it's whatever the reader
+    /// said the session is in, or its own currency
+    fn vend_currency(&self) -> u16 {
+        self.session_currency.unwrap_or(self.country_code)
+    }
+
+    /// Currency codes are only sent to the reader in multicurrency mode - otherwise
+    /// the only acceptable currency is the reader's own.
+    fn check_currency(&self, currency: u16) -> Result<(), ()> {
+        if self.enabled_options.multicurrency || currency == self.country_code {
+            Ok(())
+        } else {
+            error!("Currency {=u16:#x} requested but multicurrency not enabled", currency);
+            Err(())
+        }
+    }
+
+    //Once enhanced item number information is enabled, vend request, negative vend request,
+    //vend success and cash sale also carry the item info block - blank if the caller only gave
+    //us an address.  Begin session requests and remote vend denials never carry it.
+    fn write_item_info(&self, item: Option<&ItemDescription>, buf: &mut [u8]) -> usize {
+        if !self.enabled_options.enhanced_item_number_information {
+            return 0;
+        }
+        match item {
+            Some(item) => item.write_info(buf),
+            None => buf[..ITEM_INFO_LEN].fill(b' '),
+        }
+        ITEM_INFO_LEN
+    }
+
+    /// Sends the max/min price the VMC will ask for, in whichever monetary format is enabled.
+    /// In expanded currency mode the prices are followed by the currency code they apply to -
+    /// in multicurrency mode, call this once for each currency the machine sells in.
+    pub async fn send_max_min_prices<T: Read + Write>(
+        &self,
+        bus: &mut Mdb<T>,
+        max_price: u32,
+        min_price: u32,
+        currency: u16,
+    ) -> Result<(), ()> {
+        self.check_currency(currency)?;
+        let mut msg: [u8; 12] = [0x00; 12];
+        msg[0] = SETUP_PREFIX;
+        msg[1] = SETUP_MAX_MIN_PRICES;
+        let mut len = 2;
+        //"Don't know" max price is all 1s, whichever format is in use
+        let max_price = if self.enabled_options.monetary_format_32_bit { max_price } else { max_price.min(u16::MAX as u32) };
+        len += self.write_amount(max_price, &mut msg[len..]).ok_or(())?;
+        len += self.write_amount(min_price, &mut msg[len..]).ok_or(())?;
+        if self.enabled_options.monetary_format_32_bit {
+            msg[len..len + 2].copy_from_slice(&currency.to_be_bytes());
+            len += 2;
+        }
+        bus.send_data_and_confirm_ack(&msg[..len]).await
+    }
 
-impl CashlessDevice {
     /// Given the first byte of the poll command, this function will
     /// return its' length.  Needed in order to tokenize multiple
     /// responses to a poll command when they are chained into a single message
@@ -271,18 +1368,17 @@ impl CashlessDevice {
         match poll_cmd {
             POLL_REPLY_JUST_RESET => Ok(1),
             POLL_REPLY_READER_CONFIG_DATA => Ok(8),
-            POLL_REPLY_DISPLAY_REQUEST => Ok(2), //NB If you implement a reader with rows/cols, then the
-            //number of bytes here are rows*cols.  We don't have any rows/cols, so we shouldnt
-            //ever receive this message....
+            //Display time, then rows*cols of text for whatever display size we told the reader we have
+            POLL_REPLY_DISPLAY_REQUEST => Ok(2 + self.vmc_config.display_rows as usize * self.vmc_config.display_columns as usize),
             POLL_REPLY_BEGIN_SESSION => {
                 match self.feature_level {
                     CashlessDeviceFeatureLevel::Level1 => Ok(3),
+                    _ if self.enabled_options.monetary_format_32_bit => Ok(17),
                     _ => Ok(10),
-                    //Would be 17 if expanded currency mode enabled, but not supported currently
                 }
             }
             POLL_REPLY_SESSION_CANCEL_REQUEST => Ok(1),
-            POLL_REPLY_VEND_APPROVED => Ok(3), //NB would be 5 if expanded currency mode is enabled
+            POLL_REPLY_VEND_APPROVED => Ok(1 + self.amount_length()),
             POLL_REPLY_VEND_DENIED => Ok(1),
             POLL_REPLY_END_SESSION => Ok(1),
             POLL_REPLY_CANCELLED => Ok(1),
@@ -301,9 +1397,14 @@ impl CashlessDevice {
             },
             POLL_REPLY_REVALUE_APPROVED => Ok(1),
             POLL_REPLY_REVALUE_DENIED => Ok(1),
-            POLL_REPLY_REVALUE_LIMIT_AMOUNT => Ok(3),
+            POLL_REPLY_REVALUE_LIMIT_AMOUNT => Ok(1 + self.amount_length()),
             POLL_REPLY_TIME_DATE_REQUEST => Ok(1),
             POLL_REPLY_DATA_ENTRY_REQUEST => Ok(2),
+            POLL_REQUEST_DATA_ENTRY_CANCEL => Ok(1),
+            POLL_REPLY_REMOTE_VEND_REQUEST => Ok(1 + self.amount_length() + 2),
+            POLL_REPLY_BASKET_APPROVED => Ok(1 + self.amount_length() + 1),
+            POLL_REPLY_COUPON_REPORT => Ok(2 + self.amount_length()),
+            POLL_REPLY_BEGIN_SESSION_REFUSED => Ok(1),
             _ => {
                 debug!("Invalid poll event byte {=u8}", poll_cmd);
                 Err(())
@@ -311,7 +1412,7 @@ impl CashlessDevice {
         }
     }
 
-    pub async fn init<T: Read + Write>(bus: &mut Mdb<T>) -> Option<Self> {
+    pub async fn init<T: Read + Write>(bus: &mut Mdb<T>, vmc_config: VmcConfig) -> Option<Self> {
         //MDB spec insists on following init sequence for cashless devices:
         //Reset
         //Poll - should reply POLL_REPLY_JUST_RESET
@@ -341,183 +1442,91 @@ impl CashlessDevice {
             }
         }
 
-        //VMC/device config data exchange
-        bus.send_data(&VMC_SETUP_DATA).await;
-        if let Ok(MDBResponse::Data(len)) = bus.receive_response(&mut buf).await {
-            if len != 8 {
-                error!("Cashless device incorrect setup length {}", len);
-                return None;
-            }
-        } else {
-            error!("Cashless device failed to reply with setup data");
+        if vmc_config.display_rows as usize * vmc_config.display_columns as usize > MAX_DISPLAY_CHARS {
+            error!("VMC display too large - max {} characters", MAX_DISPLAY_CHARS);
             return None;
         }
 
-        //Parse the setup data from buffer
-        let feature_level = match buf[0x01] {
-            0x02 => CashlessDeviceFeatureLevel::Level2,
-            0x03 => CashlessDeviceFeatureLevel::Level3,
-            _ => CashlessDeviceFeatureLevel::Level1,
+        //VMC/device config data exchange
+        bus.send_data(&[
+            SETUP_PREFIX,
+            SETUP_CONFIG_DATA,
+            vmc_config.feature_level as u8,
+            vmc_config.display_columns,
+            vmc_config.display_rows,
+            vmc_config.display_type as u8,
+        ]).await;
+        let config = match bus.receive_response(&mut buf).await {
+            Ok(MDBResponse::Data(len)) => match ReaderConfigData::parse(&buf[..len]) {
+                Some(config) => config,
+                None => {
+                    error!("Cashless device incorrect setup reply (length {})", len);
+                    return None;
+                }
+            },
+            _ => {
+                error!("Cashless device failed to reply with setup data");
+                return None;
+            }
         };
+        //A reader talking to a lower level VMC behaves as that level
+        let feature_level = core::cmp::min(config.feature_level, vmc_config.feature_level);
 
-        let country_code: u16 = (buf[0x02] as u16) << 8 | buf[0x03] as u16;
-        let scale_factor = buf[0x04];
-        let decimal_places = buf[0x05];
-        let max_response_time = buf[0x06];
-        //Optional feature flags
-        let can_restore_funds = buf[0x07] & 0x01 != 0;
-        let multivend_capable = buf[0x07] & 0x02 != 0;
-        let has_display = buf[0x07] & 0x04 != 0;
-        let supports_cash_sale_cmd = buf[0x07] & 0x08 != 0;
-
-        //L3 features (only if we are a l3 reader)
-        let supports_ftl;
-        let monetary_format_32_bit;
-        let supports_multicurrency;
-        let supports_negative_vend;
-        let supports_data_entry;
-        let supports_always_idle;
-
-        //Newly added L3 features in 2019 spec
-        let supports_remote_vend;
-        let supports_basket;
-        let supports_coupon;
-        let supports_ask_begin_session;
-        let supports_enhanced_item_number_information;
-
-        //Min max price data
-        let _ = bus.send_data_and_confirm_ack(&VMC_MAX_MIN_PRICE_DATA).await;
+        //Min max price data - always 16 bit at this point, "don't know" max if ours won't fit
+        let max_price = vmc_config.max_price.min(u16::MAX as u32) as u16;
+        let min_price = vmc_config.min_price.min(u16::MAX as u32) as u16;
+        let _ = bus.send_data_and_confirm_ack(&[
+            SETUP_PREFIX,
+            SETUP_MAX_MIN_PRICES,
+            (max_price >> 8) as u8,
+            (max_price & 0xFF) as u8,
+            (min_price >> 8) as u8,
+            (min_price & 0xFF) as u8,
+        ]).await;
 
         //Expansion request
-        bus.send_data(&VMC_EXPANSION_REQUEST_ID_DATA).await; //as above
-        if let Ok(MDBResponse::Data(len)) = bus.receive_response(&mut buf).await {
-            if matches!(feature_level, CashlessDeviceFeatureLevel::Level3) {
-                if len != 34 {
-                    error!(
-                        "L3 cashless device replied with wrong length expansion data ( {} )",
-                        len
-                    );
+        bus.send_data(&vmc_config.identity.request_id_message()).await;
+        let id = match bus.receive_response(&mut buf).await {
+            Ok(MDBResponse::Data(len)) => match PeripheralId::parse(&buf[..len]) {
+                //L3 readers send 34 bytes including their option bits, L1-2 30 bytes
+                Some(id) if id.options.is_some() == (feature_level == CashlessDeviceFeatureLevel::Level3) => id,
+                _ => {
+                    error!("Cashless device replied with wrong length expansion data ( {} )", len);
                     return None;
                 }
-            } else if len != 30 {
-                //30 bytes if level 1-2
-                error!(
-                    "Non L3 cashless device replied with wrong length expansion data ( {} )",
-                    len
-                );
+            },
+            _ => {
+                error!("Cashless device failed to reply with expansion request data");
                 return None;
             }
-        } else {
-            error!("Cashless device failed to reply with expansion request data");
-            return None;
         };
 
-        match feature_level {
-            CashlessDeviceFeatureLevel::Level3 => {
-                //Original level 3 features
-                supports_ftl = buf[33] & 0x01 != 0;
-                monetary_format_32_bit = buf[33] & 0x02 != 0;
-                supports_multicurrency = buf[33] & 0x04 != 0;
-                supports_negative_vend = buf[33] & 0x08 != 0;
-                supports_data_entry = buf[33] & 0x10 != 0;
-                supports_always_idle = buf[33] & 0x20 != 0;
-
-                //Newly added L3 features in 2019 spec
-                supports_remote_vend = buf[33] & 0x40 != 0;
-                supports_basket = buf[33] & 0x80 != 0;
-                supports_coupon = buf[32] & 0x01 != 0;
-                supports_ask_begin_session = buf[32] & 0x02 != 0;
-                supports_enhanced_item_number_information = buf[32] & 0x04 != 0;
-            }
-            _ => {
-                //L1-2 readers wont support any of these
-                supports_ftl = false;
-                monetary_format_32_bit = false;
-                supports_multicurrency = false;
-                supports_negative_vend = false;
-                supports_data_entry = false;
-                supports_always_idle = false;
-
-                //Newly added L3 features in 2019 spec
-                supports_remote_vend = false;
-                supports_basket = false;
-                supports_coupon = false;
-                supports_ask_begin_session = false;
-                supports_enhanced_item_number_information = false;
-            }
-        }
-
-        //Buffer will now contain correct length of data for parsing expansion request
-        let c = CashlessDevice {
-            feature_level,
-            country_code,
-            scale_factor,
-            decimal_places,
-            max_response_time,
+        let mut c = CashlessDevice::from_setup(vmc_config, feature_level, &config, id);
 
-            //Basic option flags
-            can_restore_funds,
-            multivend_capable,
-            has_display,
-            supports_cash_sale_cmd,
+        if matches!(c.feature_level, CashlessDeviceFeatureLevel::Level3) {
+            //Enable what we asked for, if the reader supports it
+            let mut options = c.vmc_config.requested_options.intersection(&c.supported_options);
+            //We do not support FTL
+            options.ftl = false;
+            //Multicurrency needs the 32 bit BEGIN SESSION
+            options.multicurrency &= options.monetary_format_32_bit;
 
-            //Data from the expansion request
-            manufacturer_code: {
-                match from_utf8(&buf[1..4]) {
-                    Ok(a) => str4::from(a),
-                    Err(_) => {
-                        error!("Non-ascii text in mfr code");
-                        str4::from("")
-                    }
-                }
-            },
-            serial_number: {
-                match from_utf8(&buf[4..16]) {
-                    Ok(a) => str16::from(a),
-                    Err(_) => {
-                        error!("Non-ascii text in mfr code");
-                        str16::from("")
-                    }
-                }
-            },
-            model_number: {
-                match from_utf8(&buf[16..28]) {
-                    Ok(a) => str16::from(a),
-                    Err(_) => {
-                        error!("Non-ascii text in mfr code");
-                        str16::from("")
-                    }
-                }
-            },
-            software_version: {
-                match from_utf8(&buf[28..30]) {
-                    Ok(a) => str4::from(a),
-                    Err(_) => {
-                        error!("Non-ascii text in mfr code");
-                        str4::from("")
-                    }
+            let bits = options.bits().to_be_bytes();
+            match bus.send_data_and_confirm_ack(&[EXPANSION_PREFIX, EXPANSION_FEATURE_ENABLE, bits[0], bits[1], bits[2], bits[3]]).await {
+                Ok(_) => {
+                    debug!("Option feature enable command ACKd - enabled {}", options);
+                    c.enabled_options = options;
                 }
-            },
-            //L3 features
-            supports_ftl,
-            monetary_format_32_bit,
-            supports_multicurrency,
-            supports_negative_vend,
-            supports_data_entry,
-            supports_always_idle,
-
-            //Newly added L3 features in 2019 spec
-            supports_remote_vend,
-            supports_basket,
-            supports_coupon,
-            supports_ask_begin_session,
-            supports_enhanced_item_number_information,
-        };
+                Err(_) => error!("Option feature enable command NAK"),
+            }
+        }
 
-        //Enable our desired optional features
-        match bus.send_data_and_confirm_ack(&FEATURE_FLAG_DATA).await {
-            Ok(_) => debug!("Option feature enable command ACKd"),
-            Err(_) => error!("Option feature enable command NAK"),
+        if c.enabled_options.monetary_format_32_bit {
+            //Spec requires max/min price to be resent in the 32 bit format
+            match c.send_max_min_prices(bus, c.vmc_config.max_price, c.vmc_config.min_price, c.country_code).await {
+                Ok(_) => debug!("32 bit max/min prices sent"),
+                Err(_) => error!("32 bit max/min prices not ACKd"),
+            }
         }
 
         //Device not enabled by default, you'll need to enable it
@@ -527,66 +1536,199 @@ impl CashlessDevice {
     pub async fn record_cash_transaction<T: Read + Write>(
         &self,
         bus: &mut Mdb<T>,
-        unscaled_amount: u16,
+        unscaled_amount: u32,
         address: [u8; 2],
     ) -> Result<(),()> {
-        let amount = unscaled_amount.to_le_bytes();
-        bus.send_data_and_confirm_ack(&[
-            VEND_PREFIX,
-            VEND_CASH_SALE,
-            amount[1],
-            amount[0],
-            address[0],
-            address[1],
-        ])
-        .await 
+        self.record_cash_transaction_in_currency(bus, unscaled_amount, self.country_code, address).await
     }
 
-    pub async fn start_transaction<T: Read + Write>(
+    /// As record_cash_transaction, but for a sale made in a given currency (multicurrency mode)
+    pub async fn record_cash_transaction_in_currency<T: Read + Write>(
+        &self,
+        bus: &mut Mdb<T>,
+        unscaled_amount: u32,
+        currency: u16,
+        address: [u8; 2],
+    ) -> Result<(),()> {
+        self.send_cash_sale(bus, unscaled_amount, currency, address, None).await
+    }
+
+    /// As record_cash_transaction, but describing the item sold.  The product code and
+    /// description are only sent if enhanced item number information was enabled.
+    pub async fn record_item_cash_transaction<T: Read + Write>(
+        &self,
+        bus: &mut Mdb<T>,
+        unscaled_amount: u32,
+        item: &ItemDescription,
+    ) -> Result<(),()> {
+        self.send_cash_sale(bus, unscaled_amount, self.country_code, item.item_number.to_be_bytes(), Some(item))
+            .await
+    }
+
+    async fn send_cash_sale<T: Read + Write>(
         &self,
         bus: &mut Mdb<T>,
-        unscaled_amount: u16,
+        unscaled_amount: u32,
+        currency: u16,
         address: [u8; 2],
+        item: Option<&ItemDescription>,
     ) -> Result<(),()> {
-        let amount = unscaled_amount.to_le_bytes();
-            bus.send_data_and_confirm_ack(&[
-            VEND_PREFIX,
-            VEND_REQUEST,
-            amount[1],
-            amount[0],
-            address[0],
-            address[1],
-        ])
-        .await 
-    }
-
-    pub async fn cancel_transaction<T: Read + Write>(&self, bus: &mut Mdb<T>) -> Result<(),()> {
+        self.require_state(&[SessionState::Disabled, SessionState::Enabled, SessionState::SessionIdle])?;
+        self.check_currency(currency)?;
+        let mut msg: [u8; 36] = [0x00; 36];
+        msg[0] = VEND_PREFIX;
+        msg[1] = VEND_CASH_SALE;
+        let mut len = 2;
+        len += self.write_amount(unscaled_amount, &mut msg[len..]).ok_or(())?;
+        msg[len..len + 2].copy_from_slice(&address);
+        len += 2;
+        if self.enabled_options.monetary_format_32_bit {
+            //Cash sale also carries the currency in expanded currency mode
+            msg[len..len + 2].copy_from_slice(&currency.to_be_bytes());
+            len += 2;
+        }
+        len += self.write_item_info(item, &mut msg[len..]);
+        bus.send_data_and_confirm_ack(&msg[..len]).await
+    }
+
+    /// Request a vend priced in the session's currency (the reader's own outside a session).
+    /// The reader may approve or deny it straight away, otherwise the answer arrives through poll.
+    pub async fn start_transaction<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        unscaled_amount: u32,
+        address: [u8; 2],
+    ) -> Result<VendOutcome,()> {
+        self.start_transaction_in_currency(bus, unscaled_amount, self.vend_currency(), address).await
+    }
+
+    /// As start_transaction, but priced in a given currency (multicurrency mode).
+    /// VEND REQUEST has no currency field, so this must be the currency the reader reported
+    /// in BEGIN SESSION (or its own currency outside a session) - anything else is refused.
+    pub async fn start_transaction_in_currency<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        unscaled_amount: u32,
+        currency: u16,
+        address: [u8; 2],
+    ) -> Result<VendOutcome,()> {
+        self.send_vend_request(bus, unscaled_amount, currency, address, None, true).await
+    }
+
+    /// As start_transaction, but describing the item being vended.  The product code and
+    /// description are only sent if enhanced item number information was enabled.
+    pub async fn start_item_transaction<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        unscaled_amount: u32,
+        item: &ItemDescription,
+    ) -> Result<VendOutcome,()> {
+        self.send_vend_request(bus, unscaled_amount, self.vend_currency(), item.item_number.to_be_bytes(), Some(item), true)
+            .await
+    }
+
+    async fn send_vend_request<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        unscaled_amount: u32,
+        currency: u16,
+        address: [u8; 2],
+        item: Option<&ItemDescription>,
+        apply_coupon: bool,
+    ) -> Result<VendOutcome,()> {
+        self.require_vend_allowed()?;
+        self.check_currency(currency)?;
+        let session_currency = self.vend_currency();
+        if currency != session_currency {
+            error!("Vend priced in {=u16:#x} but the session is in {=u16:#x}", currency, session_currency);
+            return Err(());
+        }
+        //An accepted coupon applies to the customer's next vend, and is used up once it succeeds
+        let coupon = if apply_coupon { self.accepted_coupon } else { None };
+        let list_price = unscaled_amount;
+        let unscaled_amount = match coupon {
+            Some(coupon) => coupon.discounted_price(list_price),
+            None => list_price,
+        };
+        let mut msg: [u8; 36] = [0x00; 36];
+        msg[0] = VEND_PREFIX;
+        msg[1] = VEND_REQUEST;
+        let mut len = 2;
+        len += self.write_amount(unscaled_amount, &mut msg[len..]).ok_or(())?;
+        msg[len..len + 2].copy_from_slice(&address);
+        len += 2;
+        len += self.write_item_info(item, &mut msg[len..]);
+        let reply = self.send_and_receive_event(bus, &msg[..len]).await?;
+        self.start_vend_record(list_price, coupon, unscaled_amount);
+        self.set_state(SessionState::Vend);
+        self.vend_outcome(bus, reply).await
+    }
+
+    /// Cancel the vend in progress - nothing is charged
+    pub async fn cancel_transaction<T: Read + Write>(&mut self, bus: &mut Mdb<T>) -> Result<VendRecord,()> {
+        self.require_state(&[SessionState::Vend])?;
         let mut buf:[u8;1] = [0x00;1];
         bus.send_data(&[VEND_PREFIX, VEND_CANCEL]).await;
         if let Ok(MDBResponse::Data(1)) = bus.receive_response(&mut buf).await {
             if buf[0] == POLL_REPLY_CANCELLED {
-                return Ok(());   
+                let record = self.take_vend_record(0);
+                self.set_state(SessionState::SessionIdle);
+                return Ok(record);
             }
         }
         debug!("Unexpected reply to cancel transaction");
         Err(())
     }
 
-    pub async fn vend_success<T: Read + Write>(&self, bus: &mut Mdb<T>, address: [u8; 2]) -> Result<(),()> {
-        bus.send_data_and_confirm_ack(&[VEND_PREFIX, VEND_SUCCESS, address[0], address[1]])
-            .await 
+    /// Report the item was vended - returns what was actually charged
+    pub async fn vend_success<T: Read + Write>(&mut self, bus: &mut Mdb<T>, address: [u8; 2]) -> Result<VendRecord,()> {
+        self.send_vend_success(bus, address, None).await
+    }
+
+    /// As vend_success, but describing the item vended
+    pub async fn item_vend_success<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        item: &ItemDescription,
+    ) -> Result<VendRecord,()> {
+        self.send_vend_success(bus, item.item_number.to_be_bytes(), Some(item)).await
+    }
+
+    async fn send_vend_success<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        address: [u8; 2],
+        item: Option<&ItemDescription>,
+    ) -> Result<VendRecord,()> {
+        self.require_state(&[SessionState::Vend, SessionState::NegativeVend])?;
+        let mut msg: [u8; 28] = [0x00; 28];
+        msg[0] = VEND_PREFIX;
+        msg[1] = VEND_SUCCESS;
+        msg[2..4].copy_from_slice(&address);
+        let len = 4 + self.write_item_info(item, &mut msg[4..]);
+        bus.send_data_and_confirm_ack(&msg[..len]).await?;
+        let record = self.complete_vend(true, u32::MAX);
+        self.set_state(SessionState::SessionIdle);
+        Ok(record)
     }
 
-    pub async fn vend_failed<T: Read + Write>(&self, bus: &mut Mdb<T>) -> Result<(),()> {
+    /// Report the item could not be vended - nothing is charged
+    pub async fn vend_failed<T: Read + Write>(&mut self, bus: &mut Mdb<T>) -> Result<VendRecord,()> {
+        self.require_state(&[SessionState::Vend, SessionState::NegativeVend])?;
         bus.send_data_and_confirm_ack(&[VEND_PREFIX, VEND_FAILURE])
-            .await
+            .await?;
+        let record = self.complete_vend(false, 0);
+        self.set_state(SessionState::SessionIdle);
+        Ok(record)
     }
 
-    pub async fn end_session<T: Read + Write>(&self, bus: &mut Mdb<T>) ->  Result<(),()> {
+    pub async fn end_session<T: Read + Write>(&mut self, bus: &mut Mdb<T>) ->  Result<(),()> {
+        self.require_state(&[SessionState::SessionIdle])?;
         let mut buf:[u8;1] = [0x00;1];
         bus.send_data(&[VEND_PREFIX,  VEND_SESSION_COMPLETE]).await;
         if let Ok(response) = bus.receive_response(&mut buf).await {
             if matches!(response, MDBResponse::Data(1)) && buf[0] == POLL_REPLY_END_SESSION {
+                self.set_state(SessionState::Enabled);
                 return Ok(());
             }
         }
@@ -594,22 +1736,404 @@ impl CashlessDevice {
         Err(())
     }
 
-    pub async fn set_device_enabled<T: Read + Write>(
+    /// Ask for one authorisation covering several items.  The reader answers, straight away or
+    /// through poll, with a basket approval (saying which items it approved) or a denial.
+    /// Vend the approved items, then report what happened with basket_vend_result.
+    pub async fn basket_vend_request<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        items: &[BasketItem],
+    ) -> Result<VendOutcome, ()> {
+        if !self.enabled_options.basket {
+            error!("Basket vend not enabled");
+            return Err(());
+        }
+        self.require_vend_allowed()?;
+        let mut msg: [u8; 36] = [0x00; 36];
+        msg[0] = VEND_PREFIX;
+        msg[1] = VEND_BASKET_REQUEST;
+        msg[2] = items.len() as u8;
+        let mut len = 3;
+        if items.is_empty() || items.len() > MAX_BASKET_ITEMS || len + items.len() * (self.amount_length() + 2) > msg.len() {
+            error!("Basket vend request needs 1-{} items, and must fit one message", MAX_BASKET_ITEMS);
+            return Err(());
+        }
+        for item in items {
+            len += self.write_amount(item.price, &mut msg[len..]).ok_or(())?;
+            msg[len..len + 2].copy_from_slice(&item.item_number.to_be_bytes());
+            len += 2;
+        }
+        let reply = self.send_and_receive_event(bus, &msg[..len]).await?;
+        self.basket[..items.len()].copy_from_slice(items);
+        self.basket_len = items.len();
+        let total = items.iter().fold(0u32, |total, item| total.saturating_add(item.price));
+        self.start_vend_record(total, None, total);
+        self.set_state(SessionState::Vend);
+        self.vend_outcome(bus, reply).await
+    }
+
+    /// Report which basket items were vended - bit n of vended_items set if item n was dispensed.
+    /// Items not vended are not charged for, and items the reader didn't approve are left out.
+    pub async fn basket_vend_result<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        vended_items: u8,
+    ) -> Result<VendRecord, ()> {
+        self.require_state(&[SessionState::Vend])?;
+        if self.basket_len == 0 {
+            error!("No basket vend in progress");
+            return Err(());
+        }
+        if vended_items & !self.basket_approved_items != 0 {
+            warn!(
+                "Basket items {=u8:#x} reported vended but not approved - ignoring them",
+                vended_items & !self.basket_approved_items
+            );
+        }
+        let vended_items = vended_items & self.basket_approved_items;
+        bus.send_data_and_confirm_ack(&[VEND_PREFIX, VEND_BASKET_RESULT, vended_items]).await?;
+        //Charge for what was actually vended, but never more than was approved
+        let charged = self.basket[..self.basket_len]
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| vended_items & (0x01 << i) != 0)
+            .fold(0u32, |total, (_, item)| total.saturating_add(item.price));
+        let record = self.complete_vend(vended_items != 0, charged);
+        self.set_state(SessionState::SessionIdle);
+        Ok(record)
+    }
+
+    /// Ask the reader to start a session, eg once the customer has picked a selection before
+    /// presenting payment.  The price and item let the reader prompt for the right amount.
+    /// The reader answers through poll with a begin session, or BeginSessionRefused.
+    pub async fn request_begin_session<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        unscaled_amount: u32,
+        address: [u8; 2],
+    ) -> Result<(), ()> {
+        if !self.enabled_options.ask_begin_session {
+            error!("Ask begin session not enabled");
+            return Err(());
+        }
+        self.require_state(&[SessionState::Enabled])?;
+        let mut msg: [u8; 8] = [0x00; 8];
+        msg[0] = VEND_PREFIX;
+        msg[1] = VEND_ASK_BEGIN_SESSION;
+        let mut len = 2;
+        len += self.write_amount(unscaled_amount, &mut msg[len..]).ok_or(())?;
+        msg[len..len + 2].copy_from_slice(&address);
+        len += 2;
+        bus.send_data_and_confirm_ack(&msg[..len]).await
+    }
+
+    /// Accept or reject the coupon the reader just reported.  An accepted coupon's discount
+    /// is applied to the customer's next vend request (not remote vends), and kept until a
+    /// discounted vend succeeds.
+    pub async fn coupon_reply<T: Read + Write>(&mut self, bus: &mut Mdb<T>, accept: bool) -> Result<(), ()> {
+        if !self.enabled_options.coupon {
+            error!("Coupons not enabled");
+            return Err(());
+        }
+        let coupon = self.pending_coupon.take().ok_or_else(|| {
+            error!("No coupon to reply to");
+        })?;
+        let status = if accept { COUPON_ACCEPTED } else { COUPON_REJECTED };
+        bus.send_data_and_confirm_ack(&[VEND_PREFIX, VEND_COUPON_REPLY, status]).await?;
+        if accept {
+            self.accepted_coupon = Some(coupon);
+        }
+        Ok(())
+    }
+
+    /// Accept a remote vend request - this sends the VEND REQUEST for the item and price the
+    /// reader asked for.  The reader then approves or denies it as for any vend, after which
+    /// dispense the item and report the result with vend_success/vend_failed.
+    pub async fn accept_remote_vend<T: Read + Write>(&mut self, bus: &mut Mdb<T>) -> Result<VendOutcome, ()> {
+        let request = self.pending_remote_vend()?;
+        //The reader set the price, so no coupon applies
+        let address = request.item_number.to_be_bytes();
+        let outcome = self.send_vend_request(bus, request.price, self.vend_currency(), address, None, false).await?;
+        self.pending_remote_vend = None;
+        Ok(outcome)
+    }
+
+    /// Refuse a remote vend request, eg because the selection is sold out
+    pub async fn deny_remote_vend<T: Read + Write>(&mut self, bus: &mut Mdb<T>) -> Result<(), ()> {
+        let request = self.pending_remote_vend()?;
+        let item = request.item_number.to_be_bytes();
+        bus.send_data_and_confirm_ack(&[VEND_PREFIX, VEND_REMOTE_VEND_DENIED, item[0], item[1]]).await?;
+        self.pending_remote_vend = None;
+        if self.remote_vend_session {
+            self.set_state(SessionState::Enabled);
+        }
+        Ok(())
+    }
+
+    //Only cleared once the reply is sent, so a refused or NAKd reply can be retried
+    fn pending_remote_vend(&self) -> Result<RemoteVendRequest, ()> {
+        if !self.enabled_options.remote_vend {
+            error!("Remote vend not enabled");
+            return Err(());
+        }
+        self.pending_remote_vend.ok_or_else(|| {
+            error!("No remote vend request pending");
+        })
+    }
+
+    /// Ends the session if it has been idle for longer than the configured session timeout.
+    /// Call periodically (eg alongside poll).  Returns true if the session was ended.
+    pub async fn check_session_timeout<T: Read + Write>(&mut self, bus: &mut Mdb<T>) -> Result<bool, ()> {
+        let timeout = match self.vmc_config.session_timeout {
+            Some(t) => t,
+            None => return Ok(false),
+        };
+        if self.state != SessionState::SessionIdle {
+            return Ok(false);
+        }
+        match self.last_session_activity {
+            Some(last) if last.elapsed() > timeout => {
+                debug!("Cashless session timed out");
+                self.end_session(bus).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Send a command which the reader may answer either with an ACK, or immediately with
+    /// a single poll-style event.  Returns None if it was just ACKd.
+    async fn send_and_receive_event<T: Read + Write>(
+        &self,
+        bus: &mut Mdb<T>,
+        msg: &[u8],
+    ) -> Result<Option<PollEvent>, ()> {
+        let mut buf: [u8; 36] = [0x00; 36];
+        bus.send_data(msg).await;
+        match bus.receive_response(&mut buf).await {
+            Ok(MDBResponse::StatusMsg(MDBStatus::ACK)) => Ok(None),
+            Ok(MDBResponse::Data(len)) => {
+                match self.poll_response_length(buf[0]) {
+                    Ok(event_len) if event_len <= len => {
+                        PollEvent::try_from(&buf[..event_len]).map(Some).map_err(|_| {
+                            error!("Invalid reply data: {=[u8]:#04x}", buf[..len]);
+                        })
+                    }
+                    _ => {
+                        error!("Unexpected reply: {=[u8]:#04x}", buf[..len]);
+                        Err(())
+                    }
+                }
+            }
+            _ => {
+                debug!("No ACK or reply to command");
+                Err(())
+            }
+        }
+    }
+
+    /// Refuse an approval our partial approval policy doesn't allow - the reader refunds the
+    /// customer, and the vend's record is left in refused_vend.  Returns true if refused.
+    /// If the reader doesn't ACK the refusal we're still in the vend, so the next poll tries again.
+    async fn refuse_unacceptable_approval<T: Read + Write>(&mut self, bus: &mut Mdb<T>) -> bool {
+        if self.state != SessionState::Vend || self.approval_acceptable() {
+            return false;
+        }
+        warn!(
+            "Cashless device approved {} for a vend of {} - refusing",
+            self.approved_amount, self.requested_amount
+        );
+        let result = if self.basket_len > 0 {
+            self.basket_vend_result(bus, 0x00).await
+        } else {
+            self.vend_failed(bus).await
+        };
+        match result {
+            Ok(record) => self.refused_vend = Some(record),
+            Err(_) => error!("Cashless device did not ACK refusal of approval"),
+        }
+        true
+    }
+
+    /// Apply the reader's immediate answer (if any) to a vend, basket or negative vend request
+    async fn vend_outcome<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        reply: Option<PollEvent>,
+    ) -> Result<VendOutcome, ()> {
+        if let Some(event) = &reply {
+            self.apply_event(event);
+        }
+        let outcome = match reply {
+            None => return Ok(VendOutcome::Pending),
+            Some(PollEvent::VendApproved(amount)) => VendOutcome::Approved(amount),
+            Some(PollEvent::BasketApproved(approval)) => VendOutcome::BasketApproved(approval),
+            Some(PollEvent::VendDenied) => return Ok(VendOutcome::Denied),
+            Some(_) => {
+                error!("Unexpected reply to vend request");
+                return Err(());
+            }
+        };
+        if self.refuse_unacceptable_approval(bus).await {
+            Ok(VendOutcome::Denied)
+        } else {
+            Ok(outcome)
+        }
+    }
+
+    /// Ask the reader to credit the customer's card for an item returned into the machine
+    /// (eg a bottle return, or a refund).  Only allowed if negative vend was enabled.
+    pub async fn negative_vend_request<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        unscaled_amount: u32,
+        address: [u8; 2],
+        item: Option<&ItemDescription>,
+    ) -> Result<VendOutcome, ()> {
+        if !self.enabled_options.negative_vend {
+            error!("Negative vend not enabled");
+            return Err(());
+        }
+        self.require_vend_allowed()?;
+        let mut msg: [u8; 32] = [0x00; 32];
+        msg[0] = VEND_PREFIX;
+        msg[1] = NEGATIVE_VEND_REQUEST;
+        let mut len = 2;
+        len += self.write_amount(unscaled_amount, &mut msg[len..]).ok_or(())?;
+        msg[len..len + 2].copy_from_slice(&address);
+        len += 2;
+        len += self.write_item_info(item, &mut msg[len..]);
+        let reply = self.send_and_receive_event(bus, &msg[..len]).await?;
+        self.start_vend_record(unscaled_amount, None, unscaled_amount);
+        self.set_state(SessionState::NegativeVend);
+        self.vend_outcome(bus, reply).await
+    }
+
+    /// Ask the reader to add unscaled_amount to the customer's card, eg leftover cash credit
+    /// that would otherwise be paid out as change.  Level 2/3 readers only.
+    pub async fn revalue<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        unscaled_amount: u32,
+    ) -> Result<RevalueOutcome, ()> {
+        if matches!(self.feature_level, CashlessDeviceFeatureLevel::Level1) {
+            error!("Revalue not supported by level 1 readers");
+            return Err(());
+        }
+        self.require_state(&[SessionState::SessionIdle])?;
+        let mut msg: [u8; 6] = [0x00; 6];
+        msg[0] = VEND_REVALUE_PREFIX;
+        msg[1] = VEND_REVALUE_REQUEST;
+        let len = 2 + self.write_amount(unscaled_amount, &mut msg[2..]).ok_or(())?;
+        let reply = self.send_and_receive_event(bus, &msg[..len]).await?;
+        self.set_state(SessionState::Revalue);
+        if let Some(event) = &reply {
+            self.apply_event(event);
+        }
+        match reply {
+            None => Ok(RevalueOutcome::Pending),
+            Some(PollEvent::RevalueApproved) => Ok(RevalueOutcome::Approved),
+            Some(PollEvent::RevalueDenied) => Ok(RevalueOutcome::Denied),
+            Some(_) => {
+                error!("Unexpected reply to revalue request");
+                Err(())
+            }
+        }
+    }
+
+    /// Ask the reader how much can be added to the customer's card.  Level 2/3 readers only.
+    pub async fn revalue_limit_request<T: Read + Write>(
+        &self,
+        bus: &mut Mdb<T>,
+    ) -> Result<RevalueOutcome, ()> {
+        if matches!(self.feature_level, CashlessDeviceFeatureLevel::Level1) {
+            error!("Revalue not supported by level 1 readers");
+            return Err(());
+        }
+        self.require_state(&[SessionState::SessionIdle])?;
+        match self.send_and_receive_event(bus, &[VEND_REVALUE_PREFIX, VEND_REVALUE_LIMIT_REQUEST]).await? {
+            None => Ok(RevalueOutcome::Pending),
+            Some(PollEvent::RevalueLimitAmount(amount)) => Ok(RevalueOutcome::LimitAmount(amount)),
+            //A reader that can't revalue this card says so with a denial
+            Some(PollEvent::RevalueDenied) => Ok(RevalueOutcome::Denied),
+            Some(_) => {
+                error!("Unexpected reply to revalue limit request");
+                Err(())
+            }
+        }
+    }
+
+    /// Send the keys the customer entered in answer to the pending DataEntryRequest.
+    /// Each key is a value 0x0-0xF (0-9 for digits), packed two to a byte, and there must be
+    /// exactly as many as the reader asked for.
+    pub async fn data_entry_response<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        keys: &[u8],
+    ) -> Result<(), ()> {
+        let request = self.pending_data_entry.ok_or_else(|| {
+            error!("No data entry request to respond to");
+        })?;
+        if keys.len() != request.length as usize {
+            error!("Reader asked for {=u8} keys, not {=usize}", request.length, keys.len());
+            return Err(());
+        }
+        let mut msg: [u8; 10] = [0x00; 10];
+        msg[0] = VEND_READER_PREFIX;
+        msg[1] = VEND_READER_DATA_ENTRY_RESP;
+        for (i, key) in keys.iter().enumerate() {
+            let shift = if i % 2 == 0 { 4 } else { 0 };
+            msg[2 + i / 2] |= (key & 0x0F) << shift;
+        }
+        bus.send_data_and_confirm_ack(&msg).await?;
+        self.pending_data_entry = None;
+        Ok(())
+    }
+
+    /// Set the reader's clock
+    pub async fn write_time_date<T: Read + Write>(
         &self,
         bus: &mut Mdb<T>,
+        date_time: &DateTime,
+    ) -> Result<(), ()> {
+        bus.send_data_and_confirm_ack(&date_time.write_time_date_message()).await
+    }
+
+    pub async fn set_device_enabled<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
         enable: bool,
     ) -> Result<(),()> {
-        let cmd = if enable {
-            VEND_READER_ENABLE
+        //Enable only makes sense from disabled, but we can disable at any time (ending any session)
+        let (cmd, state) = if enable {
+            self.require_state(&[SessionState::Disabled, SessionState::Enabled])?;
+            (VEND_READER_ENABLE, SessionState::Enabled)
         } else {
-            VEND_READER_DISABLE
+            if self.state == SessionState::Inactive {
+                error!("Cannot disable an inactive cashless device");
+                return Err(());
+            }
+            (VEND_READER_DISABLE, SessionState::Disabled)
         };
         bus.send_data_and_confirm_ack(&[VEND_READER_PREFIX, cmd])
-            .await
+            .await?;
+        //Either way the application has now said what it wants
+        self.reenable_after_setup = false;
+        self.set_state(state);
+        Ok(())
+    }
+
+    /// Repeat the whole setup sequence with the same VmcConfig, eg once the reader has lost
+    /// its configuration.  Leaves the reader disabled.  poll does this itself when needed.
+    pub async fn reinit<T: Read + Write>(&mut self, bus: &mut Mdb<T>) -> Result<(), ()> {
+        let reenable_after_setup = self.reenable_after_setup;
+        *self = Self::init(bus, self.vmc_config).await.ok_or(())?;
+        self.reenable_after_setup = reenable_after_setup;
+        Ok(())
     }
 
     pub async fn poll<T: Read + Write>(
-        &self,
+        &mut self,
         bus: &mut Mdb<T>,
     ) -> Result<[Option<PollEvent>; 36],()> {
         let mut events: [Option<PollEvent>; 36] = [None; 36];
@@ -630,6 +2154,7 @@ impl CashlessDevice {
                                     match PollEvent::try_from(&buf[index..index + event_len]) {
                                         Ok(event) => {
                                             debug!("Parsed a poll event: {=[u8]:#04x}", buf[index..index + event_len]);
+                                            self.apply_event(&event);
                                             events[event_count] = Some(event);
                                             event_count += 1;
                                         }
@@ -665,6 +2190,273 @@ impl CashlessDevice {
                 return Err(());
             }
         };
+
+        if self.refuse_unacceptable_approval(bus).await {
+            for event in events.iter_mut().flatten() {
+                if matches!(event, PollEvent::VendApproved(_) | PollEvent::BasketApproved(_)) {
+                    *event = PollEvent::VendDenied;
+                }
+            }
+        }
+
+        //Answer any time/date requests, if the application gave us a clock
+        if let Some(time_source) = self.vmc_config.time_source {
+            if events.iter().flatten().any(|e| matches!(e, PollEvent::TimeDateRequest)) {
+                match self.write_time_date(bus, &time_source()).await {
+                    Ok(_) => debug!("Sent time/date to cashless device"),
+                    Err(_) => error!("Cashless device did not ACK time/date"),
+                }
+            }
+        }
+
+        //Reader reset itself, or says it is inactive - set it up again.  If this fails we're
+        //still inactive, so the next poll tries again.
+        if self.state == SessionState::Inactive {
+            warn!("Cashless device needs setting up again");
+            if self.reinit(bus).await.is_err() {
+                error!("Cashless device setup failed");
+            }
+        }
+        //Re-enable it if it was enabled when it dropped out - retried each poll until it works
+        if self.reenable_after_setup
+            && self.state == SessionState::Disabled
+            && self.set_device_enabled(bus, true).await.is_err()
+        {
+            error!("Cashless device not re-enabled after setup");
+        }
         Ok(events)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BasketItem, BeginSessionAdvancedData, CashlessDevice, CashlessDeviceFeatureLevel, Coupon, CouponType,
+        DateTime, L3Options, PartialApprovalPolicy, PaymentMediaType, PeripheralId, PollEvent, ReaderConfigData,
+        SessionState, VmcConfig,
+    };
+    use super::{
+        FEATURE_32_BIT_MONETARY_FORMAT, FEATURE_ALWAYS_IDLE, FEATURE_BASKET, FEATURE_COUPON,
+        FEATURE_ENHANCED_ITEM_NUMBER_INFORMATION, FEATURE_MULTICURRENCY, FEATURE_REMOTE_VEND,
+        POLL_REPLY_PERIPHERAL_ID, SETUP_REPLY_READER_CONFIG_DATA,
+    };
+
+    #[test]
+    fn reader_config_data_is_parsed() {
+        let config = ReaderConfigData::parse(&[0x01, 0x03, 0x18, 0x26, 0x05, 0x02, 0x0A, 0x0B]).unwrap();
+        assert_eq!(config.feature_level, CashlessDeviceFeatureLevel::Level3);
+        assert_eq!(config.country_code, 0x1826);
+        assert_eq!(config.scale_factor, 5);
+        assert_eq!(config.decimal_places, 2);
+        assert_eq!(config.max_response_time, 10);
+        assert!(config.can_restore_funds);
+        assert!(config.multivend_capable);
+        assert!(!config.has_display);
+        assert!(config.supports_cash_sale_cmd);
+    }
+
+    #[test]
+    fn reader_config_data_needs_header_and_length() {
+        assert!(ReaderConfigData::parse(&[0x09, 0x03, 0x18, 0x26, 0x05, 0x02, 0x0A, 0x0B]).is_none());
+        assert!(ReaderConfigData::parse(&[0x01, 0x03, 0x18, 0x26, 0x05, 0x02, 0x0A]).is_none());
+    }
+
+    fn peripheral_id_bytes() -> [u8; 34] {
+        let mut bytes: [u8; 34] = [0x00; 34];
+        bytes[0] = POLL_REPLY_PERIPHERAL_ID;
+        bytes[1..4].copy_from_slice(b"ABC");
+        bytes[4..16].copy_from_slice(b"123456789012");
+        bytes[16..28].copy_from_slice(b"MODEL-0001  ");
+        bytes[28..30].copy_from_slice(b"12");
+        bytes[30..34].copy_from_slice(&(FEATURE_32_BIT_MONETARY_FORMAT | FEATURE_BASKET).to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn peripheral_id_is_parsed() {
+        let bytes = peripheral_id_bytes();
+        let id = PeripheralId::parse(&bytes).unwrap();
+        assert_eq!(id.manufacturer_code.as_str(), "ABC");
+        assert_eq!(id.serial_number.as_str(), "123456789012");
+        assert_eq!(id.model_number.as_str(), "MODEL-0001  ");
+        assert_eq!(id.software_version.as_str(), "12");
+        let options = id.options.unwrap();
+        assert!(options.monetary_format_32_bit && options.basket);
+        assert!(!options.multicurrency);
+
+        //L1-2 readers don't send option bits
+        assert!(PeripheralId::parse(&bytes[..30]).unwrap().options.is_none());
+    }
+
+    #[test]
+    fn peripheral_id_needs_header_and_length() {
+        let mut bytes = peripheral_id_bytes();
+        assert!(PeripheralId::parse(&bytes[..32]).is_none());
+        bytes[0] = SETUP_REPLY_READER_CONFIG_DATA;
+        assert!(PeripheralId::parse(&bytes).is_none());
+    }
+
+    #[test]
+    fn l3_option_bits_round_trip() {
+        let bits = FEATURE_ALWAYS_IDLE | FEATURE_REMOTE_VEND | FEATURE_ENHANCED_ITEM_NUMBER_INFORMATION;
+        let options = L3Options::from_bits(bits);
+        assert!(options.always_idle && options.remote_vend && options.enhanced_item_number_information);
+        assert!(!options.ftl && !options.coupon);
+        assert_eq!(options.bits(), bits);
+        //Unknown bits are dropped
+        assert_eq!(L3Options::from_bits(0x8000_0000).bits(), 0);
+    }
+
+    #[test]
+    fn l3_option_intersection() {
+        let requested = L3Options::from_bits(FEATURE_32_BIT_MONETARY_FORMAT | FEATURE_MULTICURRENCY);
+        let supported = L3Options::from_bits(FEATURE_32_BIT_MONETARY_FORMAT | FEATURE_COUPON);
+        assert_eq!(requested.intersection(&supported).bits(), FEATURE_32_BIT_MONETARY_FORMAT);
+    }
+
+    //A level 3 reader straight out of setup, using the given approval policy
+    fn device(policy: PartialApprovalPolicy) -> CashlessDevice {
+        let config = ReaderConfigData::parse(&[0x01, 0x03, 0x18, 0x26, 0x05, 0x02, 0x0A, 0x0B]).unwrap();
+        let id = PeripheralId::parse(&peripheral_id_bytes()).unwrap();
+        let vmc_config = VmcConfig::default().with_partial_approval_policy(policy);
+        CashlessDevice::from_setup(vmc_config, CashlessDeviceFeatureLevel::Level3, &config, id)
+    }
+
+    fn vend_acceptable(policy: PartialApprovalPolicy, requested: u32, approved: u32) -> bool {
+        let mut device = device(policy);
+        device.state = SessionState::Vend;
+        device.requested_amount = Some(requested);
+        device.approved_amount = Some(approved);
+        device.approval_acceptable()
+    }
+
+    #[test]
+    fn approval_policy_for_single_vends() {
+        assert!(vend_acceptable(PartialApprovalPolicy::AcceptAny, 100, 80));
+        assert!(vend_acceptable(PartialApprovalPolicy::AcceptAny, 100, 120));
+
+        assert!(vend_acceptable(PartialApprovalPolicy::AcceptDiscount, 100, 80));
+        assert!(vend_acceptable(PartialApprovalPolicy::AcceptDiscount, 100, 100));
+        assert!(!vend_acceptable(PartialApprovalPolicy::AcceptDiscount, 100, 120));
+
+        assert!(vend_acceptable(PartialApprovalPolicy::ExactOnly, 100, 100));
+        assert!(!vend_acceptable(PartialApprovalPolicy::ExactOnly, 100, 80));
+        assert!(!vend_acceptable(PartialApprovalPolicy::ExactOnly, 100, 120));
+    }
+
+    fn basket_acceptable(policy: PartialApprovalPolicy, approved: u32, approved_items: u8) -> bool {
+        let mut device = device(policy);
+        device.state = SessionState::Vend;
+        device.basket[..3].copy_from_slice(&[
+            BasketItem { price: 50, item_number: 1 },
+            BasketItem { price: 30, item_number: 2 },
+            BasketItem { price: 20, item_number: 3 },
+        ]);
+        device.basket_len = 3;
+        device.requested_amount = Some(100);
+        device.approved_amount = Some(approved);
+        device.basket_approved_items = approved_items;
+        device.approval_acceptable()
+    }
+
+    #[test]
+    fn approval_policy_for_baskets() {
+        //Whole basket at its price
+        assert!(basket_acceptable(PartialApprovalPolicy::ExactOnly, 100, 0b111));
+        assert!(basket_acceptable(PartialApprovalPolicy::AcceptDiscount, 100, 0b111));
+
+        //Last item left out, the rest at their price - compared with the items approved, not the basket
+        assert!(basket_acceptable(PartialApprovalPolicy::AcceptDiscount, 80, 0b011));
+        assert!(!basket_acceptable(PartialApprovalPolicy::ExactOnly, 80, 0b011));
+        assert!(!basket_acceptable(PartialApprovalPolicy::AcceptDiscount, 90, 0b011));
+        assert!(basket_acceptable(PartialApprovalPolicy::AcceptAny, 90, 0b011));
+    }
+
+    #[test]
+    fn coupons_discount_the_price() {
+        let coupon = |coupon_type, value| Coupon { coupon_type, value };
+        assert_eq!(coupon(CouponType::AmountOff, 30).discounted_price(100), 70);
+        assert_eq!(coupon(CouponType::AmountOff, 150).discounted_price(100), 0);
+        assert_eq!(coupon(CouponType::PercentOff, 25).discounted_price(100), 75);
+        assert_eq!(coupon(CouponType::PercentOff, 150).discounted_price(100), 0);
+        assert_eq!(coupon(CouponType::FreeVend, 0).discounted_price(100), 0);
+        //Coupon types we don't understand leave the price alone
+        assert_eq!(coupon(CouponType::Other(0x05), 30).discounted_price(100), 100);
+    }
+
+    fn begin_session(bytes: &[u8]) -> BeginSessionAdvancedData {
+        match PollEvent::try_from(bytes) {
+            Ok(PollEvent::BeginSessionLevelAdvanced(data)) => data,
+            _ => panic!("Not an advanced begin session"),
+        }
+    }
+
+    #[test]
+    fn begin_session_payment_data_is_decoded() {
+        let data = begin_session(&[0x03, 0x01, 0xF4, 0x12, 0x34, 0x56, 0x78, 0x81, 0x05, 0x02]);
+        assert_eq!(data.funds_available(), 500);
+        assert_eq!(data.payment_media_id(), 0x1234_5678);
+        assert_eq!(data.payment_media_type(), PaymentMediaType::FreeVendCard);
+        assert_eq!(data.payment_type(), 0x81);
+        assert_eq!(data.user_group(), 5);
+        assert_eq!(data.price_list(), 2);
+        //Not in expanded currency mode, so none of these were sent
+        assert!(data.refunds_allowed().is_none());
+        assert!(data.revalue_allowed().is_none());
+        assert!(data.user_language().is_none());
+        assert!(data.currency_code().is_none());
+
+        let media_type = |payment_type| {
+            begin_session(&[0x03, 0x01, 0xF4, 0x00, 0x00, 0x00, 0x00, payment_type, 0x00, 0x00]).payment_media_type()
+        };
+        assert_eq!(media_type(0x00), PaymentMediaType::NormalVendCard);
+        assert_eq!(media_type(0x40), PaymentMediaType::TestMedia);
+        assert_eq!(media_type(0xC0), PaymentMediaType::Reserved);
+    }
+
+    #[test]
+    fn expanded_begin_session_is_parsed() {
+        let data = begin_session(&[
+            0x03, 0x00, 0x01, 0x86, 0xA0, 0x12, 0x34, 0x56, 0x78, 0x40, 0x05, 0x02, b'e', b'n', 0x09, 0x78, 0x02,
+        ]);
+        assert_eq!(data.funds_available(), 100_000);
+        assert_eq!(data.payment_media_id(), 0x1234_5678);
+        assert_eq!(data.payment_media_type(), PaymentMediaType::TestMedia);
+        assert_eq!(data.user_group(), 5);
+        assert_eq!(data.price_list(), 2);
+        assert_eq!(data.user_language(), Some(u16::from_be_bytes(*b"en")));
+        assert_eq!(data.currency_code(), Some(0x0978));
+        assert_eq!(data.refunds_allowed(), Some(false));
+        assert_eq!(data.revalue_allowed(), Some(true));
+    }
+
+    #[test]
+    fn vend_approved_is_parsed_in_both_formats() {
+        assert!(matches!(PollEvent::try_from(&[0x05, 0x01, 0xF4][..]), Ok(PollEvent::VendApproved(500))));
+        assert!(matches!(
+            PollEvent::try_from(&[0x05, 0x00, 0x01, 0x86, 0xA0][..]),
+            Ok(PollEvent::VendApproved(100_000))
+        ));
+        assert!(PollEvent::try_from(&[0x05, 0x00, 0x01, 0x86][..]).is_err());
+    }
+
+    #[test]
+    fn time_date_is_sent_as_bcd() {
+        let time = DateTime {
+            year: 2024,
+            month: 12,
+            day: 31,
+            hour: 23,
+            minute: 59,
+            second: 7,
+            day_of_week: 2,
+            week_number: 53,
+            summertime: false,
+            holiday: true,
+        };
+        assert_eq!(
+            time.write_time_date_message(),
+            [0x17, 0x03, 0x24, 0x12, 0x31, 0x23, 0x59, 0x07, 0x02, 0x53, 0x00, 0x01]
+        );
+    }
+}
@@ -29,6 +29,15 @@ const L3_PAYOUT_STATUS_CMD: u8 = 0x03;
 const L3_PAYOUT_VALUE_POLL_CMD: u8 = 0x04;
 const L3_DIAG_CMD: u8 = 0x05;
 
+//Changer status bytes we act on
+const STATUS_PAYOUT_BUSY: u8 = 0x02;
+const STATUS_CHANGER_BUSY: u8 = 0x0A;
+
+//The DISPENSE command only has 4 bits for the coin count
+const MAX_COINS_PER_DISPENSE: u8 = 15;
+//How many times (100mS apart) to poll a busy changer before giving up
+const PAYOUT_BUSY_MAX_POLLS: u8 = 100;
+
 pub enum L3OptionalFeature {
     AltPayout = 0x01,
     ExtDiag = 0x02,
@@ -41,8 +50,12 @@ pub struct CoinAcceptor {
     pub country_code: [u8; 2],
     pub scaling_factor: u8,
     pub decimal_places: u8,
-    pub coin_types: [Option<CoinType>; 16],
+    pub coin_types: [Option<CoinType>; 16], //Indexed by coin type (0-15), None if type unused
     pub l3_features: Option<CoinAcceptorL3Features>,
+    //Last masks passed to enable_coins
+    pub accept_mask: u16,
+    pub manual_dispense_mask: u16,
+    pub service_mode: bool,
 }
 
 pub struct CoinAcceptorL3Features {
@@ -81,6 +94,21 @@ pub struct ManualDispenseEvent {
     pub coins_remaining: u8, //Remaining coins
 }
 
+//Reported during float_tubes/empty_tubes
+#[derive(Copy, Clone, Format)]
+pub struct TubeServiceProgress {
+    pub coin_type: u8, //Tube being emptied
+    pub num_coins: u8, //What the changer says is left in it
+    pub target: u8,    //What we are aiming for
+}
+
+#[derive(Copy, Clone)]
+pub struct TubeCountChangedEvent {
+    pub coin_type: u8,      //Which tube changed
+    pub previous_count: u8, //What we thought the tube held before
+    pub num_coins: u8,      //What the coin acceptor now says the tube holds
+}
+
 //A poll event might be one of the following:
 #[derive(Copy, Clone)]
 pub enum PollEvent {
@@ -89,6 +117,8 @@ pub enum PollEvent {
     Status(u8),
     Coin(CoinInsertedEvent),
     ManualDispense(ManualDispenseEvent),
+    //Generated by us (not the coin acceptor) when a coin/manual dispense event moves a tube count
+    TubeCountChanged(TubeCountChangedEvent),
 }
 
 #[derive(Format, Copy, Clone)]
@@ -105,6 +135,17 @@ pub enum CoinAcceptorLevel {
     Level3,
 }
 
+//Which coins to accept, given how much change is left in the tubes
+#[derive(Copy, Clone, Format)]
+pub struct CoinInhibitPolicy {
+    pub accept_mask: u16, //The coins we'd accept if change were unlimited
+    pub min_price: u16,   //Cheapest item - a coin can never need more change than its value less this
+}
+
+fn gcd(a: u16, b: u16) -> u16 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
 impl CoinAcceptor {
     pub async fn init<T: Read + Write> (bus: &mut Mdb<T>) -> Option<Self> {
         //Start with a reset
@@ -146,13 +187,17 @@ impl CoinAcceptor {
                 scaling_factor: buf[3],
                 decimal_places: buf[4],
                 l3_features: None,
+                //Nothing accepted until enable_coins is called, manual dispense on for all coins
+                accept_mask: 0x0000,
+                manual_dispense_mask: 0xFFFF,
+                service_mode: false,
                 coin_types: {
-                    //Parse the coin type data
+                    //Parse the coin type data - the table is indexed by the changer's coin type number,
+                    //so unused types (zero credit) leave a gap rather than shifting later coins down
                     let mut types: [Option<CoinType>; 16] = [None; 16];
-                    let mut type_count: usize = 0;
-                    for (index, byte) in buf[7..23].into_iter().enumerate() {
+                    for (index, byte) in buf[7..23].iter().enumerate() {
                         if *byte != 0x00 {
-                            types[type_count] = Some(CoinType {
+                            types[index] = Some(CoinType {
                                 unscaled_value: *byte as u16 * buf[3] as u16,
                                 tube_full: false,
                                 num_coins: 0,
@@ -160,7 +205,6 @@ impl CoinAcceptor {
                                     & (0x01 << index)
                                     != 0,
                             });
-                            type_count += 1;
                         }
                     }
                     types
@@ -300,7 +344,7 @@ impl CoinAcceptor {
                 error!("Incorrect reply length -{}", count);
                 return Err(())
             }
-            let tube_full_status: u16 = (buf[0] as u16) << 8 | (buf[1] as u16) << 8;
+            let tube_full_status: u16 = (buf[0] as u16) << 8 | buf[1] as u16;
 
             for i in 0..16 {
                 if let Some(mut cointype) = self.coin_types[i].take() {
@@ -318,19 +362,68 @@ impl CoinAcceptor {
         }
     }
 
+    /// Look up a coin by the coin type number the changer uses in its messages
+    pub fn coin_type(&self, coin_type: u8) -> Option<&CoinType> {
+        self.coin_types.get(coin_type as usize)?.as_ref()
+    }
+
+    /// Record a tube count reported in a poll event, so the inventory stays current
+    /// between TUBE STATUS requests.  Returns the change, if there was one.
+    fn update_tube_count(&mut self, coin_type: u8, coins_remaining: u8) -> Option<TubeCountChangedEvent> {
+        match self.coin_types[coin_type as usize].as_mut() {
+            Some(cointype) if cointype.num_coins != coins_remaining => {
+                let change = TubeCountChangedEvent {
+                    coin_type,
+                    previous_count: cointype.num_coins,
+                    num_coins: coins_remaining,
+                };
+                debug!("Tube {} count changed {} -> {}", coin_type, cointype.num_coins, coins_remaining);
+                cointype.num_coins = coins_remaining;
+                Some(change)
+            }
+            _ => None,
+        }
+    }
+
+    /// Sets which coin types are accepted, and which can be manually dispensed using the
+    /// changer's own buttons.  Bit n of each mask corresponds to coin type n.
     pub async fn enable_coins<T: Read + Write>(
         &mut self,
         bus: &mut Mdb<T>,
         coin_mask: u16,
+        manual_dispense_mask: u16,
     ) -> Result<(), ()> {
-        //Which coins you want to enable - NB We enable manual dispense for all coins automatically.
-        if let Ok(_) = bus.send_data_and_confirm_ack(&[
+        self.accept_mask = coin_mask;
+        self.manual_dispense_mask = manual_dispense_mask;
+        self.send_coin_type(bus).await
+    }
+
+    /// In service mode, manual dispense is enabled on every coin type regardless of the
+    /// mask passed to enable_coins, so staff can empty any tube.  Leaving service mode
+    /// restores the previous manual dispense mask.
+    pub async fn set_service_mode<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        enabled: bool,
+    ) -> Result<(), ()> {
+        self.service_mode = enabled;
+        self.send_coin_type(bus).await
+    }
+
+    async fn send_coin_type<T: Read + Write>(&mut self, bus: &mut Mdb<T>) -> Result<(), ()> {
+        let manual_dispense_mask = if self.service_mode {
+            0xFFFF
+        } else {
+            self.manual_dispense_mask
+        };
+        //Both masks are sent high byte (coin types 15-8) first
+        if bus.send_data_and_confirm_ack(&[
             COIN_TYPE_CMD,
-            (coin_mask & 0xFF) as u8,
-            ((coin_mask >> 8) & 0xFF) as u8,
-            0xFF,
-            0xFF,
-        ]).await {
+            (self.accept_mask >> 8) as u8,
+            (self.accept_mask & 0xFF) as u8,
+            (manual_dispense_mask >> 8) as u8,
+            (manual_dispense_mask & 0xFF) as u8,
+        ]).await.is_ok() {
             debug!("Coins enabled OK");
             Ok(())
         }
@@ -340,6 +433,87 @@ impl CoinAcceptor {
         }
     }
 
+    /// Whether the coins currently in the tubes can pay out exactly this amount.
+    /// Mirrors the highest-coin-first strategy used by payout_level2 - so for unusual coin sets
+    /// (eg 30 and 20 paying 40) it returns false where some other combination would work,
+    /// but then payout would not find that combination either.
+    fn can_pay_exactly(&self, amount: u16) -> bool {
+        let mut remaining = amount;
+        for coin in self.coin_types.iter().rev().flatten() {
+            if coin.unscaled_value == 0 {
+                continue;
+            }
+            let num_to_pay = core::cmp::min(remaining / coin.unscaled_value, coin.num_coins as u16);
+            remaining -= num_to_pay * coin.unscaled_value;
+        }
+        remaining == 0
+    }
+
+    /// Returns true if the changer can make change for every amount up to and including
+    /// max_change, based on the current tube inventory.  Amounts are checked in steps of
+    /// the smallest unit the tube coins can make (the GCD of their values) - nothing in between
+    /// can ever be paid.  If this returns false, the VMC should show "use exact change".
+    pub fn can_make_change(&self, max_change: u16) -> bool {
+        let step = self.change_step();
+        if step == 0 {
+            //No tube coins at all
+            return max_change == 0;
+        }
+        let mut amount = step;
+        while amount <= max_change {
+            if !self.can_pay_exactly(amount) {
+                debug!("Unable to make change for {}", amount);
+                return false;
+            }
+            amount = match amount.checked_add(step) {
+                Some(a) => a,
+                None => break,
+            };
+        }
+        true
+    }
+
+    /// Smallest amount the tube coins can be combined to make - 0 if no coins go to the tubes
+    fn change_step(&self) -> u16 {
+        self.coin_types
+            .iter()
+            .flatten()
+            .filter(|coin| coin.routeable_to_tube && coin.unscaled_value != 0)
+            .fold(0, |step, coin| gcd(step, coin.unscaled_value))
+    }
+
+    /// Works out which coins of the policy's accept mask are safe to take - ie the changer
+    /// can give change for any purchase made with that coin.
+    pub fn policy_coin_mask(&self, policy: &CoinInhibitPolicy) -> u16 {
+        let mut mask: u16 = 0x0000;
+        for (i, c) in self.coin_types.iter().enumerate() {
+            if let Some(coin) = c {
+                if policy.accept_mask & (0x01 << i) == 0 {
+                    continue;
+                }
+                if self.can_make_change(coin.unscaled_value.saturating_sub(policy.min_price)) {
+                    mask |= 0x01 << i;
+                } else {
+                    debug!("Inhibiting coin type {} (value {}) - insufficient change", i, coin.unscaled_value);
+                }
+            }
+        }
+        mask
+    }
+
+    /// Enables the coins allowed by the policy given the current tube inventory.
+    /// Call again whenever the tube counts change (eg on PollEvent::TubeCountChanged or after payout)
+    /// Returns the coin mask that was enabled.
+    pub async fn apply_coin_policy<T: Read + Write>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        policy: &CoinInhibitPolicy,
+    ) -> Result<u16, ()> {
+        let mask = self.policy_coin_mask(policy);
+        self.enable_coins(bus, mask, self.manual_dispense_mask).await?;
+        Ok(mask)
+    }
+
     pub async fn payout<T: embedded_io_async::Write + embedded_io_async::Read>(
         &mut self,
         bus: &mut Mdb<T>,
@@ -372,6 +546,122 @@ impl CoinAcceptor {
         amount_paid
     }
 
+    /// Send a single DISPENSE command for up to 15 coins of one coin type
+    async fn dispense<T: Read + Write>(bus: &mut Mdb<T>, coin_type: u8, count: u8) -> Result<(), ()> {
+        let count = core::cmp::min(count, MAX_COINS_PER_DISPENSE);
+        bus.send_data_and_confirm_ack(&[DISPENSE_CMD, count << 4 | (coin_type & 0x0F)]).await
+    }
+
+    /// Polls until the changer stops reporting that it is busy paying out.
+    /// Any other poll events received meanwhile are discarded, so only use this
+    /// with coin acceptance turned off.
+    async fn wait_for_payout_complete<T: Read + Write>(&mut self, bus: &mut Mdb<T>) {
+        for _ in 0..PAYOUT_BUSY_MAX_POLLS {
+            Timer::after_millis(100).await;
+            if let Ok(events) = self.poll(bus).await {
+                let busy = events.iter().flatten().any(|e| {
+                    matches!(e, PollEvent::Status(STATUS_PAYOUT_BUSY) | PollEvent::Status(STATUS_CHANGER_BUSY))
+                });
+                if !busy {
+                    return;
+                }
+            }
+        }
+        error!("Changer still busy after payout - giving up waiting");
+    }
+
+    /// Service routine to empty tubes (target of 0) or drop them to a float level.
+    /// targets is indexed by coin type; None leaves that tube alone.
+    /// Coins are removed with DISPENSE rather than the L3 payout command, as L3 payout is
+    /// by value and lets the changer choose which tubes to use.
+    /// progress is called after every dispense with the tube status reported by the changer.
+    /// Coin acceptance is turned off while this runs (poll events are not passed on), then
+    /// put back to the last enable_coins mask.
+    /// Returns the number of coins actually removed from each tube, per the changer's tube status.
+    pub async fn float_tubes<T: Read + Write, F: FnMut(TubeServiceProgress)>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        targets: &[Option<u8>; 16],
+        progress: F,
+    ) -> Result<[u8; 16], ()> {
+        let accept_mask = self.accept_mask;
+        self.accept_mask = 0x0000;
+        let result = match self.send_coin_type(bus).await {
+            Ok(_) => self.dispense_to_targets(bus, targets, progress).await,
+            Err(_) => Err(()),
+        };
+        self.accept_mask = accept_mask;
+        if self.send_coin_type(bus).await.is_err() {
+            error!("Coin acceptance not restored after tube service");
+        }
+        result
+    }
+
+    async fn dispense_to_targets<T: Read + Write, F: FnMut(TubeServiceProgress)>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        targets: &[Option<u8>; 16],
+        mut progress: F,
+    ) -> Result<[u8; 16], ()> {
+        //Start from the changer's own counts, not what we last saw in a poll
+        self.update_coin_counts(bus).await?;
+        let mut starting_counts: [u8; 16] = [0x00; 16];
+        for (i, c) in self.coin_types.iter().enumerate() {
+            if let Some(coin) = c {
+                starting_counts[i] = coin.num_coins;
+            }
+        }
+
+        for (i, target) in targets.iter().enumerate() {
+            let target = match target {
+                Some(t) => *t,
+                None => continue,
+            };
+            loop {
+                let num_coins = match self.coin_type(i as u8) {
+                    Some(coin) if coin.routeable_to_tube => coin.num_coins,
+                    _ => break,
+                };
+                if num_coins <= target {
+                    break;
+                }
+                debug!("Tube {} has {} coins, dispensing down to {}", i, num_coins, target);
+                Self::dispense(bus, i as u8, num_coins - target).await?;
+                self.wait_for_payout_complete(bus).await;
+                self.update_coin_counts(bus).await?;
+
+                let now = self.coin_type(i as u8).map_or(0, |c| c.num_coins);
+                progress(TubeServiceProgress {
+                    coin_type: i as u8,
+                    num_coins: now,
+                    target,
+                });
+                if now >= num_coins {
+                    //Tube count didn't drop - tube empty (counts are approximate when low) or jammed
+                    error!("Tube {} did not dispense - stopping", i);
+                    break;
+                }
+            }
+        }
+
+        let mut removed: [u8; 16] = [0x00; 16];
+        for (i, c) in self.coin_types.iter().enumerate() {
+            if let Some(coin) = c {
+                removed[i] = starting_counts[i].saturating_sub(coin.num_coins);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Empty every tube, eg for cash counting
+    pub async fn empty_tubes<T: Read + Write, F: FnMut(TubeServiceProgress)>(
+        &mut self,
+        bus: &mut Mdb<T>,
+        progress: F,
+    ) -> Result<[u8; 16], ()> {
+        self.float_tubes(bus, &[Some(0); 16], progress).await
+    }
+
     pub async fn payout_level2<T: embedded_io_async::Write + embedded_io_async::Read>(
         &mut self,
         bus: &mut Mdb<T>,
@@ -390,20 +680,18 @@ impl CoinAcceptor {
                 }
 
                 while num_to_pay > 0 {
-                    //Each command can only pay out 16 coins max, so if we want to
-                    //dispense more than 16, we have to send multiple commands
-                    let num_to_dispense = if num_to_pay > 16 { 16 } else { num_to_pay };
-                    //send the command
-                    let b: u8 = i as u8 | num_to_pay << 4;
+                    //Each command can only pay out 15 coins max, so if we want to
+                    //dispense more than 15, we have to send multiple commands
+                    let num_to_dispense = core::cmp::min(num_to_pay, MAX_COINS_PER_DISPENSE);
                     defmt::debug!(
                         "Aiming to dispense {=u8} coins of type {=usize}, value {=u16}",
-                        num_to_pay,
+                        num_to_dispense,
                         i,
                         coin.unscaled_value
                     );
-                    if bus.send_data_and_confirm_ack(&[DISPENSE_CMD, b]).await.is_ok() {
+                    if Self::dispense(bus, i as u8, num_to_dispense).await.is_ok() {
                         defmt::debug!("Payout cmd acked - payout in progress");
-                        amount_paid += coin.unscaled_value * num_to_pay as u16;
+                        amount_paid += coin.unscaled_value * num_to_dispense as u16;
                         num_to_pay -= num_to_dispense;
                     } else {
                         defmt::debug!("Payout cmd not acked")
@@ -451,16 +739,13 @@ impl CoinAcceptor {
             let mut amount_paid: u16 = 0;
 
             bus.send_data(&[L3_CMD_PREFIX, L3_PAYOUT_STATUS_CMD]).await;
-            match bus.receive_response(&mut buf).await {
-                Ok(MDBResponse::Data(count)) => {
-                    for (i, byte) in buf[0..count].iter().enumerate() {
-                        self.coin_types[i].and_then(|ct| {
-                            amount_paid += ct.unscaled_value * *byte as u16;
-                            Some(ct)
-                        });
+            if let Ok(MDBResponse::Data(count)) = bus.receive_response(&mut buf).await {
+                //One byte per coin type, giving the number of that type paid out
+                for (i, byte) in buf[0..count].iter().enumerate() {
+                    if let Some(ct) = self.coin_type(i as u8) {
+                        amount_paid += ct.unscaled_value * *byte as u16;
                     }
                 }
-                _ => {}
             }
 
             amount_paid
@@ -517,7 +802,8 @@ impl CoinAcceptor {
                                     result_count += 1;
                                 } else {
                                     //It's a status - transcribe the byte across
-                                    poll_results[result_count] = Some(PollEvent::Status(*byte));                                    
+                                    poll_results[result_count] = Some(PollEvent::Status(*byte));
+                                    result_count += 1;
                                 };
                             }
                             ParseState::CoinDeposited(b) => {
@@ -525,7 +811,7 @@ impl CoinAcceptor {
                                 poll_results[result_count] = Some(PollEvent::Coin(CoinInsertedEvent {
                                     coin_type: b & 0x0F,
                                     unscaled_value: {
-                                        if let Some(ct) = self.coin_types[(b & 0x0F) as usize] {
+                                        if let Some(ct) = self.coin_type(b & 0x0F) {
                                             ct.unscaled_value
                                         } else {
                                             error!("Non existent coin deposited!");
@@ -549,6 +835,14 @@ impl CoinAcceptor {
                                 }));
                                 result_count += 1;
 
+                                //Coins routed to a tube change the tube inventory
+                                if b & 0x30 == 0x10 {
+                                    if let Some(change) = self.update_tube_count(b & 0x0F, *byte) {
+                                        poll_results[result_count] = Some(PollEvent::TubeCountChanged(change));
+                                        result_count += 1;
+                                    }
+                                }
+
                                 //Reset the state machine
                                 state = ParseState::NoState;
                             }
@@ -557,7 +851,7 @@ impl CoinAcceptor {
                                     Some(PollEvent::ManualDispense(ManualDispenseEvent {
                                         coin_type: b & 0x0F,
                                         unscaled_value: {
-                                            if let Some(ct) = self.coin_types[(b & 0x0F) as usize] {
+                                            if let Some(ct) = self.coin_type(b & 0x0F) {
                                                 ct.unscaled_value
                                             } else {
                                                 error!("Non existent coin manually dispensed!");
@@ -568,6 +862,11 @@ impl CoinAcceptor {
                                         coins_remaining: *byte,
                                     }));
                                 result_count += 1;
+
+                                if let Some(change) = self.update_tube_count(b & 0x0F, *byte) {
+                                    poll_results[result_count] = Some(PollEvent::TubeCountChanged(change));
+                                    result_count += 1;
+                                }
                                 //Reset the state machine
                                 state = ParseState::NoState;
                             }
@@ -629,4 +928,73 @@ impl CoinAcceptor {
         }
         statuses
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::{CoinAcceptor, CoinAcceptorLevel, CoinInhibitPolicy, CoinType};
+
+    fn changer(tubes: &[(u16, u8)]) -> CoinAcceptor {
+        let mut coin_types: [Option<CoinType>; 16] = [None; 16];
+        for (i, (value, count)) in tubes.iter().enumerate() {
+            coin_types[i] = Some(CoinType {
+                unscaled_value: *value,
+                routeable_to_tube: true,
+                tube_full: false,
+                num_coins: *count,
+            });
+        }
+        CoinAcceptor {
+            feature_level: CoinAcceptorLevel::Level2,
+            country_code: [0x00, 0x00],
+            scaling_factor: 1,
+            decimal_places: 2,
+            coin_types,
+            l3_features: None,
+            accept_mask: 0xFFFF,
+            manual_dispense_mask: 0xFFFF,
+            service_mode: false,
+        }
+    }
+
+    #[test]
+    fn change_steps_by_smallest_tube_coin_unit() {
+        //Scaling factor 1, but nothing below 5 can ever be paid
+        let c = changer(&[(5, 10), (10, 10), (20, 10)]);
+        assert!(c.can_make_change(100));
+    }
+
+    #[test]
+    fn change_fails_when_tubes_run_low() {
+        let c = changer(&[(5, 1), (10, 0), (20, 10)]);
+        assert!(c.can_make_change(5));
+        assert!(!c.can_make_change(10));
+    }
+
+    #[test]
+    fn no_tube_coins_means_no_change() {
+        let c = changer(&[]);
+        assert!(c.can_make_change(0));
+        assert!(!c.can_make_change(5));
+    }
+
+    #[test]
+    fn policy_inhibits_coins_needing_too_much_change() {
+        //Only two 5s left - fine for a 20 on a 10 item, not for a 50
+        let mut c = changer(&[(5, 2), (10, 0), (20, 0)]);
+        c.coin_types[3] = Some(CoinType {
+            unscaled_value: 50,
+            routeable_to_tube: false,
+            tube_full: false,
+            num_coins: 0,
+        });
+        let policy = CoinInhibitPolicy { accept_mask: 0x000F, min_price: 10 };
+        assert_eq!(c.policy_coin_mask(&policy), 0x0007);
+    }
+
+    #[test]
+    fn policy_respects_accept_mask() {
+        let c = changer(&[(5, 10), (10, 10), (20, 10)]);
+        let policy = CoinInhibitPolicy { accept_mask: 0x0003, min_price: 0 };
+        assert_eq!(c.policy_coin_mask(&policy), 0x0003);
+    }
+}
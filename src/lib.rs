@@ -147,3 +147,19 @@ impl<T: Read + Write> Mdb<T> {
         };
     }
 }
+
+//Host tests have no defmt transport - throw the log output away
+#[cfg(test)]
+mod test_logger {
+    #[defmt::global_logger]
+    struct NullLogger;
+
+    unsafe impl defmt::Logger for NullLogger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+}